@@ -0,0 +1,247 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::config::HttpConfig;
+use crate::controller::Controller;
+use crate::fault;
+use crate::journal::Source;
+use crate::lockout;
+use crate::logging;
+use crate::metrics;
+use crate::motor::Distance;
+use crate::PinDir;
+use crate::supervisor;
+
+// the control panel, served from the binary
+const INDEX_HTML: &str = include_str!("web/index.html");
+
+/*
+  Plain http control for home automation scripts, everything maps onto the Controller
+  the hive properties and buttons use.
+
+    GET  /                                the control panel
+    GET  /status                          current state as json
+    GET  /metrics                         prometheus text format, see src/metrics.rs
+    POST /move         {"direction": "up"|"down"} or {"target": 0-100}
+    POST /stop
+    POST /jog          {"distance": 200} or {"distance": "-15mm"}
+    POST /calibrate
+    POST /fault/clear
+    POST /override/resume                 ends a manual override from the buttons
+    GET  /presets                         the presets and scenes from the config
+    POST /preset       {"name": "privacy"}
+    POST /scene        {"name": "evening"}   this node's preset for the scene
+    GET  /schedule                        the schedule entries
+    PUT  /schedule     ["weekdays 07:00 open to 80%", "daily 22:00 close"]
+    PUT  /config/speed {"value": 40} or just 40
+    PUT  /config/pt    {"value": 2} or just 2
+    PUT  /config/log_level {"value": "motor=trace"} or just "info", answers with the levels
+
+  Moves and calibrate answer 423 during a manual override, see src/lockout.rs
+
+  curl -X POST -d '{"direction": "up"}' http://windyble.local:8080/move
+ */
+pub fn start(conf: HttpConfig, controller: Controller) {
+    if conf.port == 0 {
+        return;
+    }
+    // the panel finds the websocket on ws_port of the same host
+    let index = INDEX_HTML.replace("{{WS_PORT}}", &conf.ws_port.to_string());
+    supervisor::thread("http api", move || {
+        let addr = format!("0.0.0.0:{}", conf.port);
+        let server = match Server::http(addr.as_str()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to start http api on {}: {:?}", addr, e);
+                return;
+            }
+        };
+        info!("http api listening on {}", addr);
+        for request in server.incoming_requests() {
+            if request.method() == &Method::Get && request.url() == "/" {
+                serve_index(request, index.as_str());
+            } else if request.method() == &Method::Get && request.url() == "/metrics" {
+                serve_metrics(request, &controller);
+            } else {
+                handle(&controller, request);
+            }
+        }
+    });
+}
+
+fn serve_index(request: Request, index: &str) {
+    let response = Response::from_string(index)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap());
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to serve the control panel: {:?}", e);
+    }
+}
+
+fn serve_metrics(request: Request, controller: &Controller) {
+    let response = Response::from_string(metrics::render(controller))
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..]).unwrap());
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to serve metrics: {:?}", e);
+    }
+}
+
+fn handle(controller: &Controller, mut request: Request) {
+    let mut body = String::new();
+    let (status, reply) = match request.as_reader().read_to_string(&mut body) {
+        Ok(_) => {
+            debug!("{} {} {}", request.method(), request.url(), body);
+            route(controller, request.method(), request.url(), body.as_str())
+        }
+        Err(e) => error_reply(400, &format!("unreadable body: {}", e)),
+    };
+    let response = Response::from_string(reply.to_string())
+        .with_status_code(status)
+        .with_header(json_header());
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to respond to http request: {:?}", e);
+    }
+}
+
+fn route(controller: &Controller, method: &Method, url: &str, body: &str) -> (u16, Value) {
+    let path = url.split('?').next().unwrap_or("");
+    let body: Value = if body.trim().is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_str(body) {
+            Ok(v) => v,
+            Err(e) => return error_reply(400, &format!("invalid json: {}", e)),
+        }
+    };
+
+    let moves = [(Method::Post, "/move"), (Method::Post, "/jog"), (Method::Post, "/calibrate"), (Method::Post, "/preset"), (Method::Post, "/scene")];
+    if moves.iter().any(|(m, p)| m == method && *p == path) {
+        if let Err(e) = lockout::check() {
+            return error_reply(423, &e);
+        }
+    }
+
+    return match (method, path) {
+        (Method::Get, "/status") => (200, controller.status()),
+        (Method::Post, "/move") => move_blind(controller, &body),
+        (Method::Post, "/stop") => {
+            controller.stop();
+            ok_reply(controller)
+        }
+        (Method::Post, "/jog") => {
+            let distance = body.get("distance").and_then(|d| {
+                d.as_i64().map(Distance::Steps).or_else(|| d.as_str().and_then(Distance::parse))
+            });
+            match distance {
+                Some(d) if controller.jog(d, Source::Api).is_some() => ok_reply(controller),
+                Some(_) => error_reply(409, "unable to jog, busy or not calibrated"),
+                None => error_reply(400, "expected {\"distance\": steps or \"<n>mm\"}"),
+            }
+        }
+        (Method::Post, "/calibrate") => {
+            controller.calibrate(Source::Api);
+            ok_reply(controller)
+        }
+        (Method::Get, "/schedule") => (200, json!(controller.schedule().list())),
+        (Method::Put, "/schedule") => {
+            let lines: Option<Vec<String>> = body.as_array()
+                .map(|a| a.iter().filter_map(|e| e.as_str().map(String::from)).collect());
+            match lines.map(|l| controller.schedule().set_all(&l)) {
+                Some(Ok(())) => (200, json!(controller.schedule().list())),
+                Some(Err(e)) => error_reply(400, &e),
+                None => error_reply(400, "expected a list of schedule entries"),
+            }
+        }
+        (Method::Get, "/presets") => {
+            let presets = controller.presets();
+            (200, json!({ "presets": presets.positions, "scenes": presets.scenes }))
+        }
+        (Method::Post, "/preset") => match body.get("name").and_then(|n| n.as_str()) {
+            Some(name) => match controller.go_to_preset(name, Source::Api) {
+                Ok(_) => ok_reply(controller),
+                Err(e) => error_reply(409, &e),
+            },
+            None => error_reply(400, "expected {\"name\": preset}"),
+        },
+        (Method::Post, "/scene") => match body.get("name").and_then(|n| n.as_str()) {
+            Some(name) => match controller.scene(name, Source::Api) {
+                Ok(true) => ok_reply(controller),
+                Ok(false) => error_reply(404, &format!("no scene {:?} on this node", name)),
+                Err(e) => error_reply(409, &e),
+            },
+            None => error_reply(400, "expected {\"name\": scene}"),
+        },
+        (Method::Post, "/fault/clear") => {
+            fault::clear();
+            ok_reply(controller)
+        }
+        (Method::Post, "/override/resume") => {
+            lockout::resume();
+            ok_reply(controller)
+        }
+        (Method::Put, "/config/speed") => match int_value(&body) {
+            Some(speed) if (0..=100).contains(&speed) => {
+                controller.set_speed(speed);
+                ok_reply(controller)
+            }
+            _ => error_reply(400, "speed is a percentage 0 to 100"),
+        },
+        (Method::Put, "/config/pt") => match int_value(&body) {
+            Some(pt) if (0..=3).contains(&pt) => {
+                controller.set_pt(pt);
+                ok_reply(controller)
+            }
+            _ => error_reply(400, "pt is 0, 1, 2 or 3"),
+        },
+        (Method::Put, "/config/log_level") => match body.as_str().or_else(|| body.get("value").and_then(|v| v.as_str())) {
+            Some(spec) => match logging::set_level(spec) {
+                Ok(()) => (200, json!(logging::levels())),
+                Err(e) => error_reply(400, &e),
+            },
+            None => error_reply(400, "expected a level like \"debug\" or \"motor=trace\""),
+        },
+        _ => error_reply(404, "not found"),
+    };
+}
+
+fn move_blind(controller: &Controller, body: &Value) -> (u16, Value) {
+    if let Some(target) = body.get("target").and_then(|t| t.as_i64()) {
+        if !(0..=100).contains(&target) {
+            return error_reply(400, "target is a percentage 0 to 100");
+        }
+        return if controller.go_to(target, Source::Api).is_some() {
+            ok_reply(controller)
+        } else {
+            error_reply(409, "travel isn't calibrated")
+        };
+    }
+    return match body.get("direction").and_then(|d| d.as_str()) {
+        Some("up") => {
+            controller.turn(Some(PinDir::COUNTER_CLOCKWISE), Source::Api);
+            ok_reply(controller)
+        }
+        Some("down") => {
+            controller.turn(Some(PinDir::CLOCKWISE), Source::Api);
+            ok_reply(controller)
+        }
+        _ => error_reply(400, "expected {\"direction\": \"up\"|\"down\"} or {\"target\": 0-100}"),
+    };
+}
+
+// accepts a bare number or {"value": n}
+fn int_value(body: &Value) -> Option<i64> {
+    return body.as_i64().or_else(|| body.get("value").and_then(|v| v.as_i64()));
+}
+
+fn ok_reply(controller: &Controller) -> (u16, Value) {
+    return (200, controller.status());
+}
+
+fn error_reply(status: u16, message: &str) -> (u16, Value) {
+    return (status, json!({ "error": message }));
+}
+
+fn json_header() -> Header {
+    return Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+}
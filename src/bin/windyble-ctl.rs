@@ -0,0 +1,60 @@
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+use serde_json::Value;
+
+const DEFAULT_SOCKET: &str = "/tmp/windyble.sock";
+const COMMANDS: &str = "up, down, stop, status, calibrate, resume, goto <percent>, jog <steps|<n>mm>, set-speed <percent>, set-pt <0-3>, preset [<name>], scene <name>, log-level [<level>|<module>=<level>], schedule [add <entry>|remove <n>|clear]";
+
+/// Sends one command to a running windyble over its control socket and prints the reply,
+/// the socket defaults to /tmp/windyble.sock or $WINDYBLE_SOCKET
+///
+/// # Examples
+///
+/// ```
+///     windyble-ctl status
+///     windyble-ctl goto 30
+///     windyble-ctl --socket /tmp/windyble.sock set-speed 40
+/// ```
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut socket = env::var("WINDYBLE_SOCKET").unwrap_or_else(|_| String::from(DEFAULT_SOCKET));
+    if args.len() >= 2 && args[0] == "--socket" {
+        socket = args[1].clone();
+        args.drain(0..2);
+    }
+    if args.is_empty() || args[0] == "help" || args[0] == "--help" {
+        eprintln!("usage: windyble-ctl [--socket <path>] <command>\ncommands: {}", COMMANDS);
+        process::exit(2);
+    }
+
+    let reply = match send(socket.as_str(), args.join(" ").as_str()) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("windyble-ctl: {} ({})", e, socket);
+            process::exit(1);
+        }
+    };
+    match serde_json::from_str::<Value>(reply.as_str()) {
+        Ok(v) => {
+            println!("{}", serde_json::to_string_pretty(&v).unwrap_or(reply));
+            if v.get("ok").and_then(|ok| ok.as_bool()) != Some(true) {
+                process::exit(1);
+            }
+        }
+        Err(_) => {
+            println!("{}", reply);
+            process::exit(1);
+        }
+    }
+}
+
+fn send(socket: &str, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{}", command)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    return Ok(reply.trim().to_string());
+}
@@ -0,0 +1,589 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, warn, LevelFilter};
+use toml::Value;
+
+use crate::logging;
+use crate::{GpioConfig, PowerMode, GPIO_CONF};
+
+/*
+  Settings that live alongside the hive settings in the properties toml file.
+  Hive only reads the keys it knows about, so each of our sections is optional and
+  falls back to the defaults below when missing.
+
+  [hive]
+  name = "LEFT"           # this node's name on the hive
+  role = "server"         # "server" steps the motor, "client" only powers up for the server's moves,
+                          # left out it follows listen (server) or connect (client)
+  prefix = "left_"        # put in front of the [Properties] names so several blinds can share
+                          # one hive, nodes bridged to the same motor use the same prefix.
+                          # scene and override are for every node and never get the prefix
+
+  [motor]
+  hold_after = 30         # seconds powered and idle before the hold policy kicks in, 0 disables
+  hold_mode = "lowest"    # "lowest" drops to the 0.5 A current limit, "off" powers the motor down
+  power_on_settle = 500   # milliseconds between the relay closing and the first step
+  power_off_settle = 100  # milliseconds between the last step and the relay opening
+  travel_mm = 1500        # length of the full travel, lets jog take mm once the travel is calibrated
+
+  [gpio]                  # any pin left out keeps its GPIO_CONF value
+  step = 11
+  dir = 9
+  power_relay_pin = 16
+  power_mode = "relay"    # "relay", "enable" or "both"
+  relay_active_high = false
+  enable_pin = 22
+  enable_active_high = false
+  pt1 = 6
+  pt2 = 5
+  is_up_pin = 2           # the optional pins can be turned off with -1
+  is_down_pin = 3
+  go_up_pin = 18
+  go_down_pin = 17
+
+  [buttons]
+  tap_max = 400           # milliseconds, a shorter press is a tap and runs to the end stop
+  double_tap = 400        # milliseconds between a tap and the next press to count as a double tap
+  favourite = 50          # percent of the travel a double tap goes to, or the name of a preset
+  calibrate_hold = 3      # seconds both buttons are held to start a calibration run
+  override = 1800         # seconds the buttons hold off the schedule and remote moves, 0 disables
+
+  [http]
+  port = 8080             # the rest api, off unless a port is given
+  ws_port = 8081          # websocket stream of status events, off unless a port is given
+
+  [mqtt]                  # home assistant cover, off unless a host is given
+  host = "192.168.5.10"
+  port = 1883
+  node_id = "windyble_left"   # unique id, also used in the topics windyble/<node_id>/...
+  name = "Left blind"
+  discovery_prefix = "homeassistant"
+  username = "windyble"
+  password = "secret"
+
+  [control]
+  socket = "/tmp/windyble.sock"   # unix socket for windyble-ctl, "" turns it off
+
+  [state]
+  file = "state.json"     # position and travel saved on shutdown and read back on start, relative to the directory of this file, "" to not save
+
+  [schedule]              # entry format is in src/schedule.rs
+  file = "schedule.txt"   # edits are saved here and read back in place of entries on start, "" to not save
+  entries = ["weekdays 07:00 open to 80%", "daily 22:00 close"]
+
+  [logging]               # see src/logging.rs
+  level = "debug"         # off, error, warn, info, debug or trace
+  console = false         # the console argument turns it on as well
+  file = "log/output.log" # relative to the directory of this file, "" turns it off
+  max_size = 1048576      # bytes before the file is rotated
+  keep = 0                # rotated files kept, 0 starts the file over
+  syslog = false          # to the local syslog through /dev/log
+
+  [logging.modules]       # levels for single modules, overriding level
+  motor = "trace"
+  hive = "info"
+
+  [journal]               # one json line per move, see src/journal.rs
+  file = "log/moves.jsonl"    # "" turns it off
+  max_size = 1048576      # bytes before the file is rotated
+  keep = 3                # rotated files kept, moves.jsonl.1 is the newest
+
+  [location]              # for sunrise/sunset schedules, north and east are positive
+  latitude = 51.48
+  longitude = -0.01
+
+  [presets]               # named positions, percent of the travel from the top like goto
+  privacy = 70
+  morning = 30
+
+  [scenes]                # scene name to the preset this node goes to, see the scene property in main.rs
+  evening = "privacy"
+  wake = "morning"
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HoldMode {
+    Lowest,
+    Off,
+}
+
+#[derive(Clone, Copy)]
+pub struct MotorConfig {
+    pub hold_after: Duration,
+    pub hold_mode: HoldMode,
+    pub power_on_settle: Duration,
+    pub power_off_settle: Duration,
+    pub travel_mm: i64,
+}
+
+pub const MOTOR_CONF: MotorConfig = MotorConfig {
+    hold_after: Duration::from_secs(30),
+    hold_mode: HoldMode::Lowest,
+    power_on_settle: Duration::from_millis(500),
+    power_off_settle: Duration::from_millis(100),
+    travel_mm: 0,
+};
+
+#[derive(Clone, Copy)]
+pub struct ButtonConfig {
+    pub tap_max: Duration,
+    pub double_tap: Duration,
+    pub favourite: i64,
+    pub calibrate_hold: Duration,
+    pub override_for: Duration,
+}
+
+pub const BUTTON_CONF: ButtonConfig = ButtonConfig {
+    tap_max: Duration::from_millis(400),
+    double_tap: Duration::from_millis(400),
+    favourite: 50,
+    calibrate_hold: Duration::from_secs(3),
+    override_for: Duration::from_secs(30 * 60),
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HiveRole {
+    Server,
+    Client,
+}
+
+// properties every node on the hive acts on, see the scene and override properties in main.rs
+const SHARED_PROPERTIES: [&str; 2] = ["scene", "override"];
+
+#[derive(Clone)]
+pub struct HiveConfig {
+    pub name: String,
+    // None follows the hive, listening is a server and connecting a client
+    pub role: Option<HiveRole>,
+    pub prefix: String,
+}
+
+impl HiveConfig {
+    // The name one of our properties goes by on the hive
+    pub fn property(&self, name: &str) -> String {
+        if SHARED_PROPERTIES.contains(&name) {
+            return String::from(name);
+        }
+        return format!("{}{}", self.prefix, name);
+    }
+
+    /*
+      The properties file as the hive should see it: extra added to [Properties] where the file
+      doesn't have them already, and then every name prefixed
+     */
+    pub fn properties(&self, properties: &str, extra: &[(&str, Value)]) -> String {
+        if self.prefix.is_empty() && extra.is_empty() {
+            return String::from(properties);
+        }
+        let mut value = match properties.parse::<Value>() {
+            Ok(v) => v,
+            Err(_) => return String::from(properties),
+        };
+        if let Some(Value::Table(props)) = value.get_mut("Properties") {
+            for (name, v) in extra {
+                props.entry(name.to_string()).or_insert_with(|| v.clone());
+            }
+            let renamed = props.iter()
+                .map(|(name, v)| (self.property(name), v.clone()))
+                .collect();
+            *props = renamed;
+        }
+        return match toml::to_string(&value) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Unable to prefix the hive properties, using them as they are: {:?}", e);
+                String::from(properties)
+            }
+        };
+    }
+}
+
+pub const DEFAULT_HIVE_NAME: &str = "LEFT";
+
+#[derive(Clone, Copy)]
+pub struct HttpConfig {
+    // 0 leaves the server off
+    pub port: u16,
+    pub ws_port: u16,
+}
+
+#[derive(Clone)]
+pub struct MqttConfig {
+    pub host: Option<String>,
+    pub port: u16,
+    pub node_id: String,
+    pub name: String,
+    pub discovery_prefix: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        return MqttConfig {
+            host: None,
+            port: 1883,
+            node_id: String::from("windyble"),
+            name: String::from("Windyble"),
+            discovery_prefix: String::from("homeassistant"),
+            username: None,
+            password: None,
+        };
+    }
+}
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/tmp/windyble.sock";
+pub const DEFAULT_SCHEDULE_FILE: &str = "schedule.txt";
+
+pub const DEFAULT_JOURNAL_FILE: &str = "log/moves.jsonl";
+pub const DEFAULT_STATE_FILE: &str = "state.json";
+pub const DEFAULT_LOG_FILE: &str = "log/output.log";
+
+#[derive(Clone)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+    pub console: bool,
+    pub file: Option<String>,
+    pub max_size: u64,
+    pub keep: u32,
+    pub syslog: bool,
+}
+
+#[derive(Clone)]
+pub struct JournalConfig {
+    pub file: Option<String>,
+    pub max_size: u64,
+    pub keep: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct Presets {
+    pub positions: BTreeMap<String, i64>,
+    pub scenes: BTreeMap<String, String>,
+}
+
+#[derive(Clone)]
+pub struct ScheduleConfig {
+    pub entries: Vec<String>,
+    pub file: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub hive: HiveConfig,
+    pub gpio: GpioConfig,
+    pub motor: MotorConfig,
+    pub buttons: ButtonConfig,
+    pub http: HttpConfig,
+    pub mqtt: MqttConfig,
+    pub control_socket: Option<String>,
+    pub state_file: Option<String>,
+    pub schedule: ScheduleConfig,
+    pub journal: JournalConfig,
+    pub logging: LogConfig,
+    pub location: Option<Location>,
+    pub presets: Presets,
+}
+
+impl Config {
+    pub fn from_str(properties: &str) -> Config {
+        let mut config = Config {
+            hive: HiveConfig {
+                name: String::from(DEFAULT_HIVE_NAME),
+                role: None,
+                prefix: String::new(),
+            },
+            gpio: GPIO_CONF,
+            motor: MOTOR_CONF,
+            buttons: BUTTON_CONF,
+            http: HttpConfig { port: 0, ws_port: 0 },
+            mqtt: MqttConfig::default(),
+            control_socket: Some(String::from(DEFAULT_CONTROL_SOCKET)),
+            state_file: Some(String::from(DEFAULT_STATE_FILE)),
+            schedule: ScheduleConfig {
+                entries: vec![],
+                file: Some(String::from(DEFAULT_SCHEDULE_FILE)),
+            },
+            journal: JournalConfig {
+                file: Some(String::from(DEFAULT_JOURNAL_FILE)),
+                max_size: 1024 * 1024,
+                keep: 3,
+            },
+            logging: LogConfig {
+                level: LevelFilter::Debug,
+                modules: vec![],
+                console: false,
+                file: Some(String::from(DEFAULT_LOG_FILE)),
+                max_size: 1024 * 1024,
+                keep: 0,
+                syslog: false,
+            },
+            location: None,
+            presets: Presets::default(),
+        };
+        let value = match properties.parse::<Value>() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Unable to read config from properties, using defaults: {:?}", e);
+                return config;
+            }
+        };
+
+        if let Some(hive) = value.get("hive") {
+            read_hive(hive, &mut config.hive);
+        }
+        if let Some(motor) = value.get("motor") {
+            if let Some(secs) = motor.get("hold_after").and_then(|v| v.as_integer()) {
+                config.motor.hold_after = Duration::from_secs(secs.max(0) as u64);
+            }
+            match motor.get("hold_mode").and_then(|v| v.as_str()) {
+                Some("off") => config.motor.hold_mode = HoldMode::Off,
+                Some("lowest") => config.motor.hold_mode = HoldMode::Lowest,
+                Some(other) => warn!("Unknown hold_mode {:?}, using {:?}", other, config.motor.hold_mode),
+                None => {}
+            }
+            if let Some(ms) = motor.get("power_on_settle").and_then(|v| v.as_integer()) {
+                config.motor.power_on_settle = Duration::from_millis(ms.max(0) as u64);
+            }
+            if let Some(ms) = motor.get("power_off_settle").and_then(|v| v.as_integer()) {
+                config.motor.power_off_settle = Duration::from_millis(ms.max(0) as u64);
+            }
+            if let Some(mm) = motor.get("travel_mm").and_then(|v| v.as_integer()) {
+                config.motor.travel_mm = mm;
+            }
+        }
+        if let Some(gpio) = value.get("gpio") {
+            read_gpio(gpio, &mut config.gpio);
+        }
+        if let Some(presets) = value.get("presets").and_then(|v| v.as_table()) {
+            for (name, percent) in presets {
+                match percent.as_integer() {
+                    Some(p) if (0..=100).contains(&p) => {
+                        config.presets.positions.insert(name.clone(), p);
+                    }
+                    _ => warn!("preset {:?} needs a percentage 0 to 100", name),
+                }
+            }
+        }
+        if let Some(scenes) = value.get("scenes").and_then(|v| v.as_table()) {
+            for (scene, preset) in scenes {
+                match preset.as_str() {
+                    Some(p) if config.presets.positions.contains_key(p) => {
+                        config.presets.scenes.insert(scene.clone(), String::from(p));
+                    }
+                    _ => warn!("scene {:?} needs the name of a preset", scene),
+                }
+            }
+        }
+        if let Some(buttons) = value.get("buttons") {
+            if let Some(ms) = buttons.get("tap_max").and_then(|v| v.as_integer()) {
+                config.buttons.tap_max = Duration::from_millis(ms.max(0) as u64);
+            }
+            if let Some(ms) = buttons.get("double_tap").and_then(|v| v.as_integer()) {
+                config.buttons.double_tap = Duration::from_millis(ms.max(0) as u64);
+            }
+            match buttons.get("favourite") {
+                Some(Value::Integer(p)) => config.buttons.favourite = (*p).clamp(0, 100),
+                Some(Value::String(name)) => match config.presets.positions.get(name) {
+                    Some(p) => config.buttons.favourite = *p,
+                    None => warn!("favourite preset {:?} isn't in [presets]", name),
+                },
+                _ => {}
+            }
+            if let Some(secs) = buttons.get("calibrate_hold").and_then(|v| v.as_integer()) {
+                config.buttons.calibrate_hold = Duration::from_secs(secs.max(0) as u64);
+            }
+            if let Some(secs) = buttons.get("override").and_then(|v| v.as_integer()) {
+                config.buttons.override_for = Duration::from_secs(secs.max(0) as u64);
+            }
+        }
+        if let Some(http) = value.get("http") {
+            config.http.port = read_port(http, "port").unwrap_or(0);
+            config.http.ws_port = read_port(http, "ws_port").unwrap_or(0);
+        }
+        if let Some(mqtt) = value.get("mqtt") {
+            read_mqtt(mqtt, &mut config.mqtt);
+        }
+        if let Some(socket) = value.get("control").and_then(|c| read_string(c, "socket")) {
+            config.control_socket = if socket.is_empty() { None } else { Some(socket) };
+        }
+        if let Some(file) = value.get("state").and_then(|s| read_string(s, "file")) {
+            config.state_file = if file.is_empty() { None } else { Some(file) };
+        }
+        if let Some(schedule) = value.get("schedule") {
+            if let Some(file) = read_string(schedule, "file") {
+                config.schedule.file = if file.is_empty() { None } else { Some(file) };
+            }
+            if let Some(entries) = schedule.get("entries").and_then(|v| v.as_array()) {
+                config.schedule.entries = entries.iter()
+                    .filter_map(|e| e.as_str().map(String::from))
+                    .collect();
+            }
+        }
+        if let Some(journal) = value.get("journal") {
+            if let Some(file) = read_string(journal, "file") {
+                config.journal.file = if file.is_empty() { None } else { Some(file) };
+            }
+            if let Some(size) = journal.get("max_size").and_then(|v| v.as_integer()) {
+                config.journal.max_size = size.max(0) as u64;
+            }
+            if let Some(keep) = journal.get("keep").and_then(|v| v.as_integer()) {
+                config.journal.keep = keep.clamp(0, 100) as u32;
+            }
+        }
+        if let Some(logging) = value.get("logging") {
+            read_logging(logging, &mut config.logging);
+        }
+        if let Some(location) = value.get("location") {
+            // whole numbers are valid toml integers, take them too
+            let read = |key: &str| location.get(key)
+                .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)));
+            match (read("latitude"), read("longitude")) {
+                (Some(latitude), Some(longitude)) if latitude.abs() <= 90.0 && longitude.abs() <= 180.0 => {
+                    config.location = Some(Location { latitude, longitude });
+                }
+                _ => warn!("[location] needs a latitude (-90 to 90) and longitude (-180 to 180)"),
+            }
+        }
+        debug!("motor hold after {:?} ({:?})", config.motor.hold_after, config.motor.hold_mode);
+        return config;
+    }
+}
+
+fn read_pin(section: &Value, key: &str) -> Option<u8> {
+    return section.get(key)
+        .and_then(|v| v.as_integer())
+        .filter(|n| (0..=u8::MAX as i64).contains(n))
+        .map(|n| n as u8);
+}
+
+// Optional pins are left alone when missing and disabled when set to a negative number
+fn read_optional_pin(section: &Value, key: &str, pin: &mut Option<u8>) {
+    match section.get(key).and_then(|v| v.as_integer()) {
+        Some(n) if n < 0 => *pin = None,
+        Some(_) => *pin = read_pin(section, key),
+        None => {}
+    }
+}
+
+fn read_gpio(gpio: &Value, conf: &mut GpioConfig) {
+    if let Some(p) = read_pin(gpio, "step") { conf.step = p; }
+    if let Some(p) = read_pin(gpio, "dir") { conf.dir = p; }
+    if let Some(p) = read_pin(gpio, "power_relay_pin") { conf.power_relay_pin = p; }
+    if let Some(p) = read_pin(gpio, "pt1") { conf.pt1 = p; }
+    if let Some(p) = read_pin(gpio, "pt2") { conf.pt2 = p; }
+    read_optional_pin(gpio, "enable_pin", &mut conf.enable_pin);
+    read_optional_pin(gpio, "is_up_pin", &mut conf.is_up_pin);
+    read_optional_pin(gpio, "is_down_pin", &mut conf.is_down_pin);
+    read_optional_pin(gpio, "go_up_pin", &mut conf.go_up_pin);
+    read_optional_pin(gpio, "go_down_pin", &mut conf.go_down_pin);
+
+    if let Some(b) = gpio.get("relay_active_high").and_then(|v| v.as_bool()) {
+        conf.relay_active_high = b;
+    }
+    if let Some(b) = gpio.get("enable_active_high").and_then(|v| v.as_bool()) {
+        conf.enable_active_high = b;
+    }
+    match gpio.get("power_mode").and_then(|v| v.as_str()) {
+        Some("relay") => conf.power_mode = PowerMode::Relay,
+        Some("enable") => conf.power_mode = PowerMode::Enable,
+        Some("both") => conf.power_mode = PowerMode::Both,
+        Some(other) => warn!("Unknown power_mode {:?}, using {:?}", other, conf.power_mode),
+        None => {}
+    }
+    if conf.power_mode != PowerMode::Relay && conf.enable_pin.is_none() {
+        warn!("power_mode {:?} needs an enable_pin, falling back to Relay", conf.power_mode);
+        conf.power_mode = PowerMode::Relay;
+    }
+}
+
+fn read_port(section: &Value, key: &str) -> Option<u16> {
+    return match section.get(key).and_then(|v| v.as_integer()) {
+        Some(port) if port > 0 && port <= u16::MAX as i64 => Some(port as u16),
+        Some(port) => {
+            warn!("Invalid {} {}", key, port);
+            None
+        }
+        None => None,
+    };
+}
+
+fn read_string(section: &Value, key: &str) -> Option<String> {
+    return section.get(key).and_then(|v| v.as_str()).map(String::from);
+}
+
+fn read_hive(hive: &Value, conf: &mut HiveConfig) {
+    match read_string(hive, "name") {
+        Some(name) if name.trim().is_empty() => warn!("[hive] name can't be empty, using {:?}", conf.name),
+        Some(name) => conf.name = name,
+        None => {}
+    }
+    match hive.get("role").and_then(|v| v.as_str()) {
+        Some("server") => conf.role = Some(HiveRole::Server),
+        Some("client") => conf.role = Some(HiveRole::Client),
+        Some(other) => warn!("Unknown hive role {:?}, following listen/connect", other),
+        None => {}
+    }
+    if let Some(prefix) = read_string(hive, "prefix") {
+        // property names are toml keys, keep to what a bare key allows
+        if prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            conf.prefix = prefix;
+        } else {
+            warn!("[hive] prefix {:?} should only have letters, digits, _ and -, not using it", prefix);
+        }
+    }
+}
+
+fn read_mqtt(mqtt: &Value, conf: &mut MqttConfig) {
+    conf.host = read_string(mqtt, "host");
+    if let Some(port) = read_port(mqtt, "port") {
+        conf.port = port;
+    }
+    if let Some(id) = read_string(mqtt, "node_id") { conf.node_id = id; }
+    if let Some(name) = read_string(mqtt, "name") { conf.name = name; }
+    if let Some(prefix) = read_string(mqtt, "discovery_prefix") { conf.discovery_prefix = prefix; }
+    conf.username = read_string(mqtt, "username");
+    conf.password = read_string(mqtt, "password");
+}
+
+fn read_logging(logging: &Value, conf: &mut LogConfig) {
+    let level = |v: &Value| v.as_str().map(logging::parse_level);
+    match logging.get("level").and_then(level) {
+        Some(Ok(l)) => conf.level = l,
+        Some(Err(e)) => warn!("{}", e),
+        None => {}
+    }
+    if let Some(modules) = logging.get("modules").and_then(|v| v.as_table()) {
+        for (module, value) in modules {
+            match level(value) {
+                Some(Ok(l)) => conf.modules.push((module.clone(), l)),
+                Some(Err(e)) => warn!("{} for {}", e, module),
+                None => warn!("the log level for {} should be a string", module),
+            }
+        }
+    }
+    if let Some(b) = logging.get("console").and_then(|v| v.as_bool()) {
+        conf.console = b;
+    }
+    if let Some(file) = read_string(logging, "file") {
+        conf.file = if file.is_empty() { None } else { Some(file) };
+    }
+    if let Some(size) = logging.get("max_size").and_then(|v| v.as_integer()) {
+        conf.max_size = size.max(0) as u64;
+    }
+    if let Some(keep) = logging.get("keep").and_then(|v| v.as_integer()) {
+        conf.keep = keep.clamp(0, 100) as u32;
+    }
+    if let Some(b) = logging.get("syslog").and_then(|v| v.as_bool()) {
+        conf.syslog = b;
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::thread;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+
+use crate::controller::Controller;
+use crate::journal::Source;
+use crate::lockout;
+use crate::logging;
+use crate::motor::Distance;
+use crate::PinDir;
+use crate::supervisor;
+
+/*
+  Local control over a unix socket, for windyble-ctl and scripts over ssh. One command per
+  line, each answered with one line of json: {"ok": true, "status": {..}} or {"ok": false, "error": ".."}
+
+    up | down | stop | status | calibrate
+    resume                        ends a manual override, the moves below are refused during one
+    goto <percent>
+    jog <steps|mm>
+    set-speed <percent>
+    set-pt <0-3>
+    preset                        lists the presets and scenes as "result"
+    preset <name>
+    scene <name>                  this node's preset for the scene
+    log-level [<level>|<module>=<level>]   lists the log levels as "result", after setting one
+    schedule                      lists the entries as "result"
+    schedule add <entry>
+    schedule remove <n>           n as numbered by the list, starting at 1
+    schedule clear
+ */
+pub fn start(path: Option<String>, controller: Controller) {
+    let path = match path {
+        Some(p) => p,
+        None => return,
+    };
+    // a socket left behind by a previous run would make bind fail, anything else there isn't ours to remove
+    if let Ok(meta) = fs::symlink_metadata(&path) {
+        if !meta.file_type().is_socket() {
+            error!("Not opening the control socket, {} is already there and isn't a socket", path);
+            return;
+        }
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Failed to remove stale control socket {}: {:?}", path, e);
+            return;
+        }
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to open control socket {}: {:?}", path, e);
+            return;
+        }
+    };
+    // it moves the blind, only our own user gets to connect
+    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+        error!("Failed to restrict the control socket {}, closing it: {:?}", path, e);
+        stop(&Some(path));
+        return;
+    }
+    info!("control socket listening on {}", path);
+    supervisor::thread("control socket", move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    let controller = controller.clone();
+                    thread::spawn(move || serve(s, controller));
+                }
+                Err(e) => warn!("control connection failed: {:?}", e),
+            }
+        }
+    });
+}
+
+// Removes the socket on the way out, the listener thread goes with the process
+pub fn stop(path: &Option<String>) {
+    if let Some(path) = path {
+        if !is_socket(path) {
+            return;
+        }
+        if let Err(e) = fs::remove_file(path) {
+            debug!("control socket {} not removed: {:?}", path, e);
+        }
+    }
+}
+
+fn is_socket(path: &str) -> bool {
+    return fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+}
+
+fn serve(stream: UnixStream, controller: Controller) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            warn!("control socket clone failed: {:?}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        debug!("control: {}", line);
+        let reply = match execute(&controller, line.trim()) {
+            Ok(None) => json!({ "ok": true, "status": controller.status() }),
+            Ok(Some(result)) => json!({ "ok": true, "status": controller.status(), "result": result }),
+            Err(e) => json!({ "ok": false, "error": e }),
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            break;
+        }
+    }
+}
+
+fn execute(controller: &Controller, line: &str) -> Result<Option<Value>, String> {
+    let mut parts = line.split_whitespace();
+    let command = parts.next().unwrap_or("");
+    let arg = parts.next();
+    if ["up", "down", "goto", "jog", "calibrate", "preset", "scene"].contains(&command) && !(command == "preset" && arg.is_none()) {
+        lockout::check()?;
+    }
+    return match command {
+        "status" => Ok(None),
+        "up" => {
+            controller.turn(Some(PinDir::COUNTER_CLOCKWISE), Source::Control);
+            Ok(None)
+        }
+        "down" => {
+            controller.turn(Some(PinDir::CLOCKWISE), Source::Control);
+            Ok(None)
+        }
+        "stop" => {
+            controller.stop();
+            Ok(None)
+        }
+        "resume" => {
+            lockout::resume();
+            Ok(None)
+        }
+        "calibrate" => {
+            controller.calibrate(Source::Control);
+            Ok(None)
+        }
+        "goto" => {
+            let percent = int_arg(arg, 0, 100)?;
+            if controller.go_to(percent, Source::Control).is_some() { Ok(None) } else { Err(String::from("travel isn't calibrated")) }
+        }
+        "jog" => {
+            let distance = arg.and_then(Distance::parse)
+                .ok_or_else(|| String::from("usage: jog <steps|<n>mm>"))?;
+            if controller.jog(distance, Source::Control).is_some() { Ok(None) } else { Err(String::from("unable to jog, busy or not calibrated")) }
+        }
+        "set-speed" => {
+            controller.set_speed(int_arg(arg, 0, 100)?);
+            Ok(None)
+        }
+        "set-pt" => {
+            controller.set_pt(int_arg(arg, 0, 3)?);
+            Ok(None)
+        }
+        "preset" => match arg {
+            None => {
+                let presets = controller.presets();
+                Ok(Some(json!({ "presets": presets.positions, "scenes": presets.scenes })))
+            }
+            Some(name) => controller.go_to_preset(name, Source::Control).map(|_| None),
+        },
+        "scene" => match arg {
+            Some(name) => match controller.scene(name, Source::Control)? {
+                true => Ok(None),
+                false => Err(format!("no scene {:?} on this node", name)),
+            },
+            None => Err(String::from("usage: scene <name>")),
+        },
+        "log-level" => {
+            if let Some(spec) = arg {
+                logging::set_level(spec)?;
+            }
+            Ok(Some(json!(logging::levels())))
+        }
+        "schedule" => {
+            let schedule = controller.schedule();
+            match arg {
+                None => {}
+                Some("add") => schedule.add(parts.collect::<Vec<&str>>().join(" ").as_str())?,
+                Some("remove") => schedule.remove(int_arg(parts.next(), 1, i64::MAX)? as usize)?,
+                Some("clear") => schedule.set_all(&[])?,
+                Some(other) => return Err(format!("unknown schedule command {:?}", other)),
+            }
+            Ok(Some(json!(schedule.list())))
+        }
+        other => Err(format!("unknown command {:?}", other)),
+    };
+}
+
+fn int_arg(arg: Option<&str>, min: i64, max: i64) -> Result<i64, String> {
+    return match arg.map(|a| a.parse::<i64>()) {
+        Some(Ok(n)) if n >= min && n <= max => Ok(n),
+        _ => Err(format!("expected a number from {} to {}", min, max)),
+    };
+}
@@ -0,0 +1,430 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_std::channel::{bounded, unbounded, Receiver, Sender};
+use async_std::future::timeout;
+use async_std::sync::Arc;
+use async_std::task;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+use crate::config::{ButtonConfig, Presets};
+use crate::fault;
+use crate::gesture::{Button, Gesture};
+use crate::journal::Source;
+use crate::lockout;
+use crate::metrics;
+use crate::motor::{Distance, Motor};
+use crate::schedule::Schedule;
+use crate::supervisor;
+use crate::{at_limit, current_direction, current_move_state, store_move_state, MoveState, PinDir};
+
+// how long the command loop gets to answer a ping before it counts as hung
+const RESPONSIVE_WAIT: Duration = Duration::from_secs(2);
+
+/*
+  What the command loop is asked to do, in the order it was asked. Everything that moves the
+  motor or changes its settings ends up here, from the hive properties, the buttons and limit
+  switches, or any of the remote controls, and the loop is the only thing acting on them.
+  Sending never blocks, so it's safe from the hive's callbacks and from plain threads alike.
+  source is who asked for the move, for the journal. started, where there is one, is told
+  whether the move began.
+ */
+#[derive(Debug)]
+pub enum Command {
+    // start turning in a direction, ignored while already turning
+    Move { dir: u8, source: Source },
+    Stop,
+    // a fixed distance, ignored while already turning
+    Jog { distance: Distance, source: Source, started: Option<Sender<bool>> },
+    // a percentage of the travel, whatever is running is stopped first
+    GoTo { percent: i64, source: Source, started: Option<Sender<bool>> },
+    // up to the top stop and down to the bottom stop, see run
+    Calibrate(Source),
+    // power up ahead of a move, or down, behind any stop still waiting to power off. bridged
+    // is for a move another node steps, see Motor::power_bridged
+    Power { on: bool, bridged: bool },
+    SetSpeed(i64),
+    SetCurrent(i64),
+    // a limit switch changed, closed when the blind is at that end
+    LimitReached { top: bool, closed: bool },
+    ButtonPressed(Gesture),
+    // answered as soon as it comes up, for is_responsive
+    Ping(Sender<()>),
+}
+
+/*
+  The one place the move/stop/speed/pt requests go through, whichever of the hive
+  properties, the buttons or the http api they came from. Requests are sent on as a Command
+  to the loop started by run, the motor changes are published as events from there.
+ */
+#[derive(Clone)]
+pub struct Controller {
+    motor: Motor,
+    commands: Sender<Command>,
+    // the last speed asked for, for status, until then the speed the motor starts at
+    speed: Arc<AtomicI64>,
+    schedule: Schedule,
+    presets: Arc<Presets>,
+}
+
+impl Controller {
+    // The receiver goes to run, nothing happens until it does
+    pub fn new(motor: Motor, schedule: Schedule, presets: Presets) -> (Controller, Receiver<Command>) {
+        let (commands, receiver) = unbounded();
+        let speed = motor.speed();
+        let controller = Controller {
+            motor,
+            commands,
+            speed: Arc::new(AtomicI64::new(speed)),
+            schedule,
+            presets: Arc::new(presets),
+        };
+        return (controller, receiver);
+    }
+
+    pub fn send(&self, command: Command) {
+        if let Err(e) = self.commands.try_send(command) {
+            error!("command loop is gone, dropped {:?}", e.into_inner());
+        }
+    }
+
+    pub fn motor(&self) -> &Motor {
+        return &self.motor;
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        return &self.schedule;
+    }
+
+    pub fn presets(&self) -> &Presets {
+        return &self.presets;
+    }
+
+    // Start turning in direction, or stop turning with None
+    pub fn turn(&self, direction: Option<u8>, source: Source) {
+        self.send(match direction {
+            Some(dir) => Command::Move { dir, source },
+            None => Command::Stop,
+        });
+    }
+
+    // the loop waits for any step thread to exit before cutting the power
+    pub fn stop(&self) {
+        self.send(Command::Stop);
+    }
+
+    pub fn limit_reached(&self, top: bool, closed: bool) {
+        self.send(Command::LimitReached { top, closed });
+    }
+
+    pub fn button(&self, gesture: Gesture) {
+        self.send(Command::ButtonPressed(gesture));
+    }
+
+    /*
+      Stops whatever is running first. None when there's no calibrated travel to go by, otherwise
+      the receiver is told whether the move started, it's fine to drop it
+     */
+    pub fn go_to(&self, percent: i64, source: Source) -> Option<Receiver<bool>> {
+        if self.motor.travel().is_none() {
+            info!("Can't go to {}%, travel isn't calibrated", percent);
+            return None;
+        }
+        let (started, receiver) = bounded(1);
+        self.send(Command::GoTo { percent, source, started: Some(started) });
+        return Some(receiver);
+    }
+
+    // Once the receiver answers true, waiting for the motor to stop sees the whole move through
+    pub fn go_to_preset(&self, name: &str, source: Source) -> Result<Receiver<bool>, String> {
+        let percent = match self.presets.positions.get(name) {
+            Some(p) => *p,
+            None => return Err(format!("no preset {:?}", name)),
+        };
+        info!("preset {} ({}%)", name, percent);
+        return self.go_to(percent, source).ok_or_else(|| String::from("travel isn't calibrated"));
+    }
+
+    // Goes to this node's preset for the scene, Ok(false) when the scene doesn't include this node
+    pub fn scene(&self, name: &str, source: Source) -> Result<bool, String> {
+        return match self.presets.scenes.get(name) {
+            Some(preset) => self.go_to_preset(preset, source).map(|_| true),
+            None => Ok(false),
+        };
+    }
+
+    // None when it can't be done now, busy or a distance in mm without the travel, like go_to otherwise
+    pub fn jog(&self, distance: Distance, source: Source) -> Option<Receiver<bool>> {
+        if self.motor.is_running() {
+            info!("Can't jog {:?}, already turning", distance);
+            return None;
+        }
+        if let Distance::Mm(mm) = distance {
+            if self.motor.steps_for_mm(mm).is_none() {
+                info!("Can't jog {}mm, travel isn't calibrated", mm);
+                return None;
+            }
+        }
+        let (started, receiver) = bounded(1);
+        self.send(Command::Jog { distance, source, started: Some(started) });
+        return Some(receiver);
+    }
+
+    pub fn calibrate(&self, source: Source) {
+        self.send(Command::Calibrate(source));
+    }
+
+    pub fn set_speed(&self, value: i64) {
+        self.speed.store(value, Ordering::SeqCst);
+        self.send(Command::SetSpeed(value));
+    }
+
+    pub fn speed(&self) -> i64 {
+        return self.speed.load(Ordering::SeqCst);
+    }
+
+    pub fn power(&self, on: bool, bridged: bool) {
+        self.send(Command::Power { on, bridged });
+    }
+
+    pub fn set_pt(&self, value: i64) {
+        self.send(Command::SetCurrent(value));
+    }
+
+    // False when the command loop doesn't answer or the step thread stops stepping, something is hung
+    pub async fn is_responsive(&self) -> bool {
+        if !self.settled().await {
+            return false;
+        }
+        let deadline = Instant::now() + RESPONSIVE_WAIT;
+        while !self.motor.is_responsive() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            task::sleep(Duration::from_millis(50)).await;
+        }
+        return true;
+    }
+
+    // True once everything sent before has been handled, false if the loop doesn't get there in time
+    async fn settled(&self) -> bool {
+        let (answer, answered) = bounded(1);
+        self.send(Command::Ping(answer));
+        return matches!(timeout(RESPONSIVE_WAIT, answered.recv()).await, Ok(Ok(())));
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let motor = &self.motor;
+        return json!({
+            "state": match current_move_state() {
+                MoveState::UP => "up",
+                MoveState::DOWN => "down",
+                _ => "free",
+            },
+            "direction": if current_direction() == PinDir::COUNTER_CLOCKWISE { "up" } else { "down" },
+            "running": motor.is_running(),
+            "powered": motor.is_powered(),
+            "holding": motor.is_holding(),
+            "speed": self.speed(),
+            "pt": motor.current_limit(),
+            "position": motor.position(),
+            "percent": motor.position_percent(),
+            "travel": motor.travel(),
+            "fault": fault::current().map(|f| f.name()),
+            "override": lockout::until(),
+        });
+    }
+}
+
+// Where a calibration run is up to and who asked for it, it moves on at each limit switch
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Calibration {
+    // on the way to the top stop, which resets the position
+    Up(Source),
+    // on the way to the bottom stop, which records the travel
+    Down(Source),
+}
+
+// A goto on its way up to the top stop first, the position isn't trusted until it's been there
+#[derive(Debug)]
+struct Homing {
+    percent: i64,
+    source: Source,
+    // answered once the goto itself starts
+    started: Option<Sender<bool>>,
+}
+
+/*
+  The command loop, one task handling every Command in turn so a stop can't overtake the
+  move it was meant for. turning is whether the motor is on a move started by Move, rather
+  than a jog or goto, which a limit switch or Stop ends. calibrating is the calibration run
+  in progress and homing a goto that has to find the top stop first, any other move or a Stop
+  ends either of them. Starting and stopping the motor wait on the
+  step thread, so they're handed to spawn_blocking and awaited, which keeps the order without
+  holding up the rest of the runtime.
+ */
+pub fn run(controller: Controller, commands: Receiver<Command>, buttons: ButtonConfig) {
+    supervisor::task("command loop", move || {
+        let controller = controller.clone();
+        let commands = commands.clone();
+        async move {
+            let motor = controller.motor().clone();
+            let mut turning = false;
+            let mut calibrating: Option<Calibration> = None;
+            let mut homing: Option<Homing> = None;
+            while let Ok(command) = commands.recv().await {
+                debug!("command {:?}", command);
+                match command {
+                    Command::Move { dir, source } => {
+                        if motor.is_running() {
+                            info!("Already turning!");
+                        } else if at_limit(dir) {
+                            info!("Already {}!!", if dir == PinDir::COUNTER_CLOCKWISE { "UP" } else { "DOWN" });
+                        } else {
+                            calibrating = None;
+                            homing = None;
+                            turning = blocking(&motor, move |m| m.turn(dir, source)).await;
+                        }
+                    }
+                    Command::Stop => {
+                        turning = false;
+                        calibrating = None;
+                        homing = None;
+                        blocking(&motor, |m| m.stop()).await;
+                    }
+                    Command::Jog { distance, source, started } => {
+                        // refused by the motor while it's turning
+                        let ok = blocking(&motor, move |m| m.jog(distance, source)).await;
+                        if ok {
+                            turning = false;
+                            calibrating = None;
+                            homing = None;
+                        }
+                        answer(started, ok);
+                    }
+                    Command::GoTo { percent, source, started } => {
+                        turning = false;
+                        calibrating = None;
+                        homing = None;
+                        if motor.is_running() {
+                            blocking(&motor, |m| m.stop()).await;
+                        }
+                        if !motor.is_homed() && at_limit(PinDir::COUNTER_CLOCKWISE) {
+                            motor.set_top();
+                        }
+                        if motor.is_homed() {
+                            answer(started, blocking(&motor, move |m| m.go_to(percent, source)).await);
+                        } else if blocking(&motor, move |m| m.turn(PinDir::COUNTER_CLOCKWISE, source)).await {
+                            info!("going to the top stop before {}%", percent);
+                            homing = Some(Homing { percent, source, started });
+                        } else {
+                            answer(started, false);
+                        }
+                    }
+                    Command::Calibrate(source) => {
+                        turning = false;
+                        homing = None;
+                        if motor.is_running() {
+                            blocking(&motor, |m| m.stop()).await;
+                        }
+                        info!("calibrating");
+                        calibrating = if at_limit(PinDir::COUNTER_CLOCKWISE) {
+                            motor.set_top();
+                            calibrate_down(&motor, source).await
+                        } else if blocking(&motor, move |m| m.turn(PinDir::COUNTER_CLOCKWISE, source)).await {
+                            Some(Calibration::Up(source))
+                        } else {
+                            warn!("calibration couldn't start");
+                            None
+                        };
+                    }
+                    Command::Power { on: true, bridged: true } => blocking(&motor, |m| m.power_bridged()).await,
+                    Command::Power { on, .. } => blocking(&motor, move |m| m.power_motor(on)).await,
+                    Command::SetSpeed(value) => motor.set_speed(value as u64),
+                    Command::SetCurrent(value) => motor.set_potentiometer(&value),
+                    Command::LimitReached { top, closed } => {
+                        if !closed {
+                            store_move_state(MoveState::FREE);
+                            continue;
+                        }
+                        store_move_state(if top { MoveState::UP } else { MoveState::DOWN });
+                        metrics::limit_hit(top);
+                        if top {
+                            motor.set_top();
+                        } else {
+                            motor.set_bottom();
+                        }
+                        if top {
+                            if let Some(Homing { percent, source, started }) = homing.take() {
+                                blocking(&motor, |m| m.stop()).await;
+                                answer(started, blocking(&motor, move |m| m.go_to(percent, source)).await);
+                                continue;
+                            }
+                        }
+                        match (calibrating, top) {
+                            (Some(Calibration::Up(source)), true) => {
+                                blocking(&motor, |m| m.stop()).await;
+                                calibrating = calibrate_down(&motor, source).await;
+                            }
+                            (Some(Calibration::Down(_)), false) => {
+                                calibrating = None;
+                                blocking(&motor, |m| m.stop()).await;
+                                if motor.travel().is_some() {
+                                    fault::clear();
+                                }
+                                info!("calibration done, travel {:?}", motor.travel());
+                            }
+                            _ if turning => {
+                                turning = false;
+                                blocking(&motor, |m| m.stop()).await;
+                            }
+                            _ => {}
+                        }
+                    }
+                    Command::ButtonPressed(gesture) => {
+                        lockout::engage(buttons.override_for);
+                        match gesture {
+                            Gesture::Press(Button::Up) => controller.turn(Some(PinDir::COUNTER_CLOCKWISE), Source::Button),
+                            Gesture::Press(Button::Down) => controller.turn(Some(PinDir::CLOCKWISE), Source::Button),
+                            Gesture::Tap(_) => {}
+                            // a stop, so a jog, goto or calibration ends too, not just a press
+                            Gesture::Released(_) | Gesture::Stop(_) | Gesture::Both => controller.stop(),
+                            Gesture::DoubleTap(_) => {
+                                let _ = controller.go_to(buttons.favourite, Source::Button);
+                            }
+                            Gesture::BothHeld => controller.calibrate(Source::Button),
+                        }
+                    }
+                    Command::Ping(answer) => {
+                        let _ = answer.try_send(());
+                    }
+                }
+            }
+            warn!("command loop ended");
+        }
+    });
+}
+
+// The second half of a calibration run, from the top stop
+async fn calibrate_down(motor: &Motor, source: Source) -> Option<Calibration> {
+    if blocking(motor, move |m| m.turn(PinDir::CLOCKWISE, source)).await {
+        return Some(Calibration::Down(source));
+    }
+    warn!("calibration couldn't leave the top stop");
+    return None;
+}
+
+// For the sender waiting on a move to start, if there is one
+fn answer(started: Option<Sender<bool>>, ok: bool) {
+    if let Some(started) = started {
+        let _ = started.try_send(ok);
+    }
+}
+
+// Starting, stopping and moving the motor can wait on the step thread, off the async threads they go
+async fn blocking<T: Send + 'static>(motor: &Motor, f: impl FnOnce(&Motor) -> T + Send + 'static) -> T {
+    let motor = motor.clone();
+    return task::spawn_blocking(move || f(&motor)).await;
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::events::{self, Event};
+
+/*
+  What can go wrong outside the motor itself: a config or properties file we can't use, a GPIO
+  pin we can't get, a hive property that's missing, or a value from a peer or client that makes
+  no sense. Each carries a message with enough context to find the cause from the log alone.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum WindybleError {
+    Config(String),
+    Gpio(String),
+    Hive(String),
+    Protocol(String),
+}
+
+pub type Result<T> = std::result::Result<T, WindybleError>;
+
+impl WindybleError {
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            WindybleError::Config(_) => "config",
+            WindybleError::Gpio(_) => "gpio",
+            WindybleError::Hive(_) => "hive",
+            WindybleError::Protocol(_) => "protocol",
+        };
+    }
+
+    pub fn message(&self) -> &str {
+        return match self {
+            WindybleError::Config(m) | WindybleError::Gpio(m) | WindybleError::Hive(m) | WindybleError::Protocol(m) => m,
+        };
+    }
+
+    // The gpio crates' errors, with which pin and what for
+    pub fn gpio(pin: u8, what: &str, e: impl fmt::Display) -> WindybleError {
+        return WindybleError::Gpio(format!("pin {} ({}): {}", pin, what, e));
+    }
+}
+
+impl fmt::Display for WindybleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{} error: {}", self.kind(), self.message());
+    }
+}
+
+impl std::error::Error for WindybleError {}
+
+// For errors with nowhere to go, logs it and tells the event stream so a dashboard sees it too
+pub fn report(e: &WindybleError) {
+    error!("{}", e);
+    events::publish(Event::Error(e.to_string()));
+}
@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_std::channel;
+use serde_json::{json, Value};
+
+use crate::fault::Fault;
+use crate::{MoveState, PinDir};
+
+/*
+  Changes worth telling a dashboard about. Anything can publish, every subscriber
+  gets each event in order tagged with a sequence number and a timestamp.
+ */
+#[derive(Clone, Debug)]
+pub enum Event {
+    MoveState(u8),
+    Direction(u8),
+    Speed(i64),
+    Pt(i64),
+    Power(bool),
+    Running(bool),
+    Turn(i8),
+    // None when the fault is cleared
+    Fault(Option<Fault>),
+    // when the manual override ends, None once automation is back in charge
+    Override(Option<u64>),
+    // a rejected property value or other error that didn't stop us, see src/error.rs
+    Error(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub seq: u64,
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+impl Envelope {
+    pub fn to_json(&self) -> Value {
+        let (kind, value) = match &self.event {
+            Event::MoveState(s) => ("move_state", json!(match *s {
+                MoveState::UP => "up",
+                MoveState::DOWN => "down",
+                _ => "free",
+            })),
+            Event::Direction(d) => ("direction", json!(if *d == PinDir::COUNTER_CLOCKWISE { "up" } else { "down" })),
+            Event::Speed(v) => ("speed", json!(v)),
+            Event::Pt(v) => ("pt", json!(v)),
+            Event::Power(on) => ("power", json!(on)),
+            Event::Running(r) => ("running", json!(r)),
+            Event::Turn(t) => ("turn", json!(t)),
+            Event::Fault(f) => ("fault", json!(f.map(|f| f.name()))),
+            Event::Override(until) => ("override", json!(until)),
+            Event::Error(message) => ("error", json!(message)),
+        };
+        return json!({
+            "seq": self.seq,
+            "timestamp": self.timestamp,
+            "type": kind,
+            "value": value,
+        });
+    }
+}
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+static SUBSCRIBERS: Mutex<Vec<Sender<Envelope>>> = Mutex::new(Vec::new());
+static ASYNC_SUBSCRIBERS: Mutex<Vec<channel::Sender<Envelope>>> = Mutex::new(Vec::new());
+
+pub fn publish(event: Event) {
+    // holding the lock while numbering keeps seq in the order subscribers see it
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    let envelope = Envelope {
+        seq: SEQ.fetch_add(1, Ordering::SeqCst) + 1,
+        timestamp: now_millis(),
+        event,
+    };
+    // a failed send means the receiver is gone
+    subscribers.retain(|s| s.send(envelope.clone()).is_ok());
+    // the async ones are unbounded, so this only fails once the receiver is gone too
+    ASYNC_SUBSCRIBERS.lock().unwrap().retain(|s| s.try_send(envelope.clone()).is_ok());
+}
+
+pub fn subscribe() -> Receiver<Envelope> {
+    let (sender, receiver) = channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    return receiver;
+}
+
+// The same events for a task to await rather than a thread to block on
+pub fn subscribe_async() -> channel::Receiver<Envelope> {
+    let (sender, receiver) = channel::unbounded();
+    ASYNC_SUBSCRIBERS.lock().unwrap().push(sender);
+    return receiver;
+}
+
+pub fn now_millis() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[allow(unused_imports)]
+use log::{error, info};
+
+use crate::events::{self, Event};
+use crate::metrics;
+
+/*
+  The last thing that went wrong with the hardware, shown on the control panel until cleared.
+  Only one fault is kept, a newer one replaces the older.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    // moved well past the calibrated travel without a limit switch closing
+    Overtravel = 1,
+    // the step thread died mid move
+    StepThread = 2,
+    // a pin couldn't be read or driven
+    Gpio = 3,
+    // a worker thread or task panicked, see src/supervisor.rs
+    Worker = 4,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 4] = [Fault::Overtravel, Fault::StepThread, Fault::Gpio, Fault::Worker];
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Fault::Overtravel => "overtravel",
+            Fault::StepThread => "step_thread",
+            Fault::Gpio => "gpio",
+            Fault::Worker => "worker",
+        };
+    }
+
+    fn from_u8(v: u8) -> Option<Fault> {
+        return match v {
+            1 => Some(Fault::Overtravel),
+            2 => Some(Fault::StepThread),
+            3 => Some(Fault::Gpio),
+            4 => Some(Fault::Worker),
+            _ => None,
+        };
+    }
+}
+
+static CURRENT_FAULT: AtomicU8 = AtomicU8::new(0);
+
+pub fn raise(fault: Fault) {
+    error!("FAULT: {}", fault.name());
+    CURRENT_FAULT.store(fault as u8, Ordering::SeqCst);
+    metrics::fault(fault);
+    events::publish(Event::Fault(Some(fault)));
+}
+
+pub fn clear() {
+    if CURRENT_FAULT.swap(0, Ordering::SeqCst) != 0 {
+        info!("fault cleared");
+        events::publish(Event::Fault(None));
+    }
+}
+
+pub fn current() -> Option<Fault> {
+    return Fault::from_u8(CURRENT_FAULT.load(Ordering::SeqCst));
+}
@@ -0,0 +1,298 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use async_std::task;
+
+#[allow(unused_imports)]
+use log::{debug, info};
+
+use crate::config::ButtonConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Up,
+    Down,
+}
+
+impl Button {
+    fn index(&self) -> usize {
+        return match self {
+            Button::Up => 0,
+            Button::Down => 1,
+        };
+    }
+
+    fn other(&self) -> Button {
+        return match self {
+            Button::Up => Button::Down,
+            Button::Down => Button::Up,
+        };
+    }
+}
+
+/*
+  What the go_up_pin/go_down_pin presses mean:
+    Press       a button went down, start moving that way straight away
+    Tap         released within tap_max, keep going to the end stop
+    Released    released after holding, stop
+    DoubleTap   pressed again within double_tap of a tap, go to the favourite position
+    Stop        a button went down while the blind was moving, stop, its release means nothing
+    Both        the other button is already down, stop
+    BothHeld    both held for calibrate_hold, calibrate the travel
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Press(Button),
+    Stop(Button),
+    Tap(Button),
+    Released(Button),
+    DoubleTap(Button),
+    Both,
+    BothHeld,
+}
+
+#[derive(Default)]
+struct State {
+    down: [Option<Instant>; 2],
+    last_tap: [Option<Instant>; 2],
+    // set when a press was part of a double tap, a stop or both press, its release means nothing
+    ignore_release: [bool; 2],
+    // bumped on every both press so a stale calibrate timer does nothing
+    both_gen: u64,
+}
+
+#[derive(Clone)]
+pub struct Gestures {
+    conf: ButtonConfig,
+    state: Arc<Mutex<State>>,
+    // whether the blind is moving, a press then stops it rather than starting a move
+    moving: Arc<dyn Fn() -> bool + Send + Sync>,
+    handler: Arc<dyn Fn(Gesture) + Send + Sync>,
+}
+
+impl Gestures {
+    pub fn new(conf: ButtonConfig,
+               moving: impl Fn() -> bool + Send + Sync + 'static,
+               handler: impl Fn(Gesture) + Send + Sync + 'static) -> Gestures {
+        return Gestures {
+            conf,
+            state: Arc::new(Mutex::new(State::default())),
+            moving: Arc::new(moving),
+            handler: Arc::new(handler),
+        };
+    }
+
+    // Feed an input pin change in, pressed is the pin reading high
+    pub fn input(&self, button: Button, pressed: bool) {
+        let gesture = if pressed { self.pressed(button) } else { self.released(button) };
+        if let Some(g) = gesture {
+            debug!("gesture {:?}", g);
+            (self.handler)(g);
+        }
+    }
+
+    fn pressed(&self, button: Button) -> Option<Gesture> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let i = button.index();
+        state.down[i] = Some(now);
+
+        if state.down[button.other().index()].is_some() {
+            state.ignore_release = [true, true];
+            state.both_gen += 1;
+            self.start_both_timer(state.both_gen);
+            return Some(Gesture::Both);
+        }
+
+        let last_tap = state.last_tap[i].take();
+        if last_tap.is_some_and(|t| now.duration_since(t) <= self.conf.double_tap) {
+            state.ignore_release[i] = true;
+            return Some(Gesture::DoubleTap(button));
+        }
+        if (self.moving)() {
+            state.ignore_release[i] = true;
+            return Some(Gesture::Stop(button));
+        }
+        return Some(Gesture::Press(button));
+    }
+
+    fn released(&self, button: Button) -> Option<Gesture> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let i = button.index();
+        let down = state.down[i].take();
+        if state.ignore_release[i] {
+            state.ignore_release[i] = false;
+            return None;
+        }
+        return match down {
+            Some(at) if now.duration_since(at) <= self.conf.tap_max => {
+                state.last_tap[i] = Some(now);
+                Some(Gesture::Tap(button))
+            }
+            Some(_) => Some(Gesture::Released(button)),
+            None => None,
+        };
+    }
+
+    fn start_both_timer(&self, gen: u64) {
+        let clone = self.clone();
+        task::spawn(async move {
+            task::sleep(clone.conf.calibrate_hold).await;
+            let still_held = {
+                let state = clone.state.lock().unwrap();
+                state.both_gen == gen && state.down[0].is_some() && state.down[1].is_some()
+            };
+            if still_held {
+                info!("both buttons held for {:?}", clone.conf.calibrate_hold);
+                (clone.handler)(Gesture::BothHeld);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::BUTTON_CONF;
+
+    const TAP_MAX: Duration = Duration::from_millis(100);
+    const HOLD: Duration = Duration::from_millis(300);
+
+    // the gestures seen so far, with short timings to keep the tests quick, and whether the blind is moving
+    fn moving_gestures() -> (Gestures, Arc<Mutex<Vec<Gesture>>>, Arc<AtomicBool>) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let moving = Arc::new(AtomicBool::new(false));
+        let conf = ButtonConfig { tap_max: TAP_MAX, double_tap: TAP_MAX, calibrate_hold: HOLD, ..BUTTON_CONF };
+        let gestures = Gestures::new(conf, {
+            let moving = moving.clone();
+            move || moving.load(Ordering::SeqCst)
+        }, {
+            let seen = seen.clone();
+            move |g| seen.lock().unwrap().push(g)
+        });
+        return (gestures, seen, moving);
+    }
+
+    fn gestures() -> (Gestures, Arc<Mutex<Vec<Gesture>>>) {
+        let (gestures, seen, _) = moving_gestures();
+        return (gestures, seen);
+    }
+
+    fn taken(seen: &Arc<Mutex<Vec<Gesture>>>) -> Vec<Gesture> {
+        return seen.lock().unwrap().drain(..).collect();
+    }
+
+    #[test]
+    fn a_quick_release_is_a_tap() {
+        let (g, seen) = gestures();
+        g.input(Button::Up, true);
+        g.input(Button::Up, false);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Up), Gesture::Tap(Button::Up)]);
+    }
+
+    #[test]
+    fn a_held_release_stops() {
+        let (g, seen) = gestures();
+        g.input(Button::Down, true);
+        sleep(TAP_MAX * 2);
+        g.input(Button::Down, false);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Down), Gesture::Released(Button::Down)]);
+    }
+
+    #[test]
+    fn a_second_tap_is_a_double_tap() {
+        let (g, seen) = gestures();
+        g.input(Button::Up, true);
+        g.input(Button::Up, false);
+        g.input(Button::Up, true);
+        g.input(Button::Up, false);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Up), Gesture::Tap(Button::Up), Gesture::DoubleTap(Button::Up)]);
+
+        // a third press starts over
+        g.input(Button::Up, true);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Up)]);
+    }
+
+    #[test]
+    fn a_late_second_press_or_the_other_button_is_a_press() {
+        let (g, seen) = gestures();
+        g.input(Button::Up, true);
+        g.input(Button::Up, false);
+        g.input(Button::Down, true);
+        g.input(Button::Down, false);
+        sleep(TAP_MAX * 2);
+        g.input(Button::Down, true);
+        assert_eq!(taken(&seen), vec![
+            Gesture::Press(Button::Up),
+            Gesture::Tap(Button::Up),
+            Gesture::Press(Button::Down),
+            Gesture::Tap(Button::Down),
+            Gesture::Press(Button::Down),
+        ]);
+    }
+
+    #[test]
+    fn both_stops_and_held_calibrates() {
+        let (g, seen) = gestures();
+        g.input(Button::Up, true);
+        g.input(Button::Down, true);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Up), Gesture::Both]);
+        sleep(HOLD * 2);
+        assert_eq!(taken(&seen), vec![Gesture::BothHeld]);
+
+        // neither release means anything after both
+        g.input(Button::Up, false);
+        g.input(Button::Down, false);
+        assert_eq!(taken(&seen), vec![]);
+    }
+
+    #[test]
+    fn both_let_go_early_doesnt_calibrate() {
+        let (g, seen) = gestures();
+        g.input(Button::Down, true);
+        g.input(Button::Up, true);
+        g.input(Button::Up, false);
+        sleep(HOLD * 2);
+        g.input(Button::Down, false);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Down), Gesture::Both]);
+
+        // and the next press is a plain one
+        g.input(Button::Up, true);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Up)]);
+    }
+
+    #[test]
+    fn a_press_while_moving_stops() {
+        let (g, seen, moving) = moving_gestures();
+        g.input(Button::Down, true);
+        g.input(Button::Down, false);
+        moving.store(true, Ordering::SeqCst);
+        sleep(TAP_MAX * 2);
+
+        // either button, and the release after it means nothing
+        g.input(Button::Up, true);
+        sleep(TAP_MAX * 2);
+        g.input(Button::Up, false);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Down), Gesture::Tap(Button::Down), Gesture::Stop(Button::Up)]);
+
+        moving.store(false, Ordering::SeqCst);
+        g.input(Button::Up, true);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Up)]);
+    }
+
+    #[test]
+    fn a_double_tap_is_kept_while_the_first_tap_moves() {
+        let (g, seen, moving) = moving_gestures();
+        g.input(Button::Down, true);
+        g.input(Button::Down, false);
+        moving.store(true, Ordering::SeqCst);
+        g.input(Button::Down, true);
+        g.input(Button::Down, false);
+        assert_eq!(taken(&seen), vec![Gesture::Press(Button::Down), Gesture::Tap(Button::Down), Gesture::DoubleTap(Button::Down)]);
+    }
+}
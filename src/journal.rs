@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local, SecondsFormat};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+use crate::config::JournalConfig;
+use crate::rolling;
+use crate::PinDir;
+
+/*
+  One json line per move, appended to the [journal] file once the move ends, for working out
+  why a blind moved without digging through the log:
+
+    {"source":"schedule","start":"2020-10-05T07:00:00.112+01:00","end":"2020-10-05T07:00:41.870+01:00",
+     "direction":"up","steps":4120,"step_us":400,"pt":2,"ended":"limit","position":0}
+
+  ended is "stopped" (a stop or release), "limit" (a limit switch), "target" (a jog or goto
+  reached its distance), "fault", "shutdown" (slowed to a stop as we exit) or "watchdog" (the
+  power was cut from outside the move, a worker panicked or shutdown got stuck). The file is
+  rotated to .1, .2, .. once it passes max_size.
+
+  Who asked for a move comes with the command that started it, see src/controller.rs.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Hive,
+    Button,
+    Schedule,
+    Api,
+    Mqtt,
+    Control,
+    Cli,
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Source::Hive => "hive",
+            Source::Button => "button",
+            Source::Schedule => "schedule",
+            Source::Api => "api",
+            Source::Mqtt => "mqtt",
+            Source::Control => "control",
+            Source::Cli => "cli",
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ended {
+    Stopped,
+    Limit,
+    Target,
+    Fault,
+    Shutdown,
+    Watchdog,
+}
+
+impl Ended {
+    fn name(&self) -> &'static str {
+        return match self {
+            Ended::Stopped => "stopped",
+            Ended::Limit => "limit",
+            Ended::Target => "target",
+            Ended::Fault => "fault",
+            Ended::Shutdown => "shutdown",
+            Ended::Watchdog => "watchdog",
+        };
+    }
+}
+
+// What the step thread knows about a move, the rest is filled in as it ends
+pub struct Move {
+    pub source: Source,
+    pub start: DateTime<Local>,
+    pub dir: u8,
+    pub step_us: u64,
+    pub pt: i64,
+}
+
+static CONFIG: Mutex<Option<JournalConfig>> = Mutex::new(None);
+
+pub fn init(conf: JournalConfig) {
+    if let Some(file) = &conf.file {
+        info!("journal of moves in {}", file);
+    }
+    *CONFIG.lock().unwrap() = Some(conf);
+}
+
+pub fn record(m: &Move, steps: u64, ended: Ended, position: i64) {
+    let line = json!({
+        "source": m.source.name(),
+        "start": m.start.to_rfc3339_opts(SecondsFormat::Millis, false),
+        "end": Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+        "direction": if m.dir == PinDir::COUNTER_CLOCKWISE { "up" } else { "down" },
+        "steps": steps,
+        "step_us": m.step_us,
+        "pt": m.pt,
+        "ended": ended.name(),
+        "position": position,
+    });
+    // held while writing and rotating so lines from two moves can't interleave
+    let config = CONFIG.lock().unwrap();
+    let conf = match config.as_ref() {
+        Some(c) => c,
+        None => return,
+    };
+    let file = match &conf.file {
+        Some(f) => f,
+        None => return,
+    };
+    if let Err(e) = rolling::append(file, line.to_string().as_str()) {
+        warn!("Failed to write the journal {}: {:?}", file, e);
+        return;
+    }
+    if let Err(e) = rolling::rotate_if_over(file, conf.max_size, conf.keep) {
+        warn!("Failed to rotate the journal {}: {:?}", file, e);
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, info};
+
+use crate::events::{self, Event};
+
+/*
+  Someone at the buttons wins over automation: using the go buttons suspends the schedule and
+  remote moves (hive, http, mqtt, the control socket) until the override runs out or automation
+  is resumed. Stopping is never blocked. The expiry is wall clock seconds since the unix epoch,
+  so it means the same thing on every node of the hive.
+ */
+static OVERRIDE_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+// Starts the override, or pushes it back, for duration from now. A zero duration does nothing
+pub fn engage(duration: Duration) {
+    if duration.as_secs() == 0 {
+        return;
+    }
+    set_until(now_secs() + duration.as_secs());
+}
+
+// 0 or a time in the past ends the override
+pub fn set_until(until: u64) {
+    if OVERRIDE_UNTIL.swap(until, Ordering::SeqCst) != until {
+        match until_from(until) {
+            Some(t) => info!("manual override for {}s", t - now_secs()),
+            None => info!("automation resumed"),
+        }
+        events::publish(Event::Override(until_from(until)));
+    }
+}
+
+pub fn resume() {
+    set_until(0);
+}
+
+// When the override ends, None when automation is in charge
+pub fn until() -> Option<u64> {
+    return until_from(OVERRIDE_UNTIL.load(Ordering::SeqCst));
+}
+
+// Err with the reason for anything automated that wants to move the blind
+pub fn check() -> Result<(), String> {
+    return match until() {
+        Some(t) => Err(format!("manual override for another {}s, resume automation first", t - now_secs())),
+        None => Ok(()),
+    };
+}
+
+fn until_from(until: u64) -> Option<u64> {
+    return if until > now_secs() { Some(until) } else { None };
+}
+
+fn now_secs() -> u64 {
+    return events::now_millis() / 1000;
+}
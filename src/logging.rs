@@ -0,0 +1,212 @@
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use chrono::Local;
+use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+
+use crate::config::LogConfig;
+use crate::rolling;
+
+/*
+  The logger, set up from [logging] in the config. Any of the console, a size rotated file and
+  the local syslog can be on at once. Levels are per module with a default for everything else,
+  and can be changed while running with set_level, from the log_level hive property or
+  windyble-ctl log-level:
+
+    debug                 the default level
+    motor=trace           one module, windyble's own modules can leave off the windyble::
+    hive=off              the hive crate
+ */
+const SYSLOG_SOCKET: &str = "/dev/log";
+// syslog's "user" facility
+const SYSLOG_FACILITY: u8 = 1;
+
+struct Filters {
+    default: LevelFilter,
+    // module path prefix and its level, the longest matching prefix wins
+    modules: Vec<(String, LevelFilter)>,
+}
+
+struct Outputs {
+    console: bool,
+    file: Option<String>,
+    max_size: u64,
+    keep: u32,
+    syslog: Option<UnixDatagram>,
+    // level, target and message of the lines logged before configure knows where the file
+    // and syslog are, and which of them are wanted
+    pending: Option<Vec<(Level, String, String)>>,
+}
+
+static FILTERS: RwLock<Filters> = RwLock::new(Filters { default: LevelFilter::Debug, modules: Vec::new() });
+static OUTPUTS: Mutex<Outputs> = Mutex::new(Outputs {
+    console: true,
+    file: None,
+    max_size: 0,
+    keep: 0,
+    syslog: None,
+    pending: None,
+});
+
+pub struct Logger;
+
+pub static LOGGER: Logger = Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        return metadata.level() <= level_for(metadata.target());
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut outputs = OUTPUTS.lock().unwrap();
+        let message = record.args().to_string();
+        if outputs.console {
+            println!("{:?} - {}", record.level(), message);
+        }
+        if let Some(pending) = outputs.pending.as_mut() {
+            pending.push((record.level(), record.target().to_string(), message));
+            return;
+        }
+        write(&mut outputs, record.level(), &message);
+    }
+
+    fn flush(&self) {}
+}
+
+fn write(outputs: &mut Outputs, level: Level, message: &str) {
+    if let Some(file) = outputs.file.clone() {
+        let line = format!("{}: {} - {}", Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"), level, message);
+        // nowhere left to report a failure to, stderr at least gets it
+        if let Err(e) = rolling::append(&file, &line)
+            .and_then(|_| rolling::rotate_if_over(&file, outputs.max_size, outputs.keep)) {
+            eprintln!("Failed to write the log file {}: {:?}", file, e);
+        }
+    }
+    if let Some(socket) = &outputs.syslog {
+        let line = format!("<{}>windyble[{}]: {}", syslog_priority(level), process::id(), message);
+        if socket.send(line.as_bytes()).is_err() {
+            // syslog went away, stop trying rather than fail on every line
+            outputs.syslog = None;
+        }
+    }
+}
+
+// Logs to the console only when to_console, everything is held for configure to write out
+pub fn init(to_console: bool) -> Result<(), SetLoggerError> {
+    {
+        let mut outputs = OUTPUTS.lock().unwrap();
+        outputs.console = to_console;
+        outputs.pending = Some(vec![]);
+    }
+    log::set_logger(&LOGGER)?;
+    update_max_level();
+    return Ok(());
+}
+
+// Switches to the [logging] config, to_console keeps the console on whatever it says
+pub fn configure(conf: &LogConfig, to_console: bool) {
+    {
+        let mut filters = FILTERS.write().unwrap();
+        filters.default = conf.level;
+        filters.modules = conf.modules.clone();
+    }
+    update_max_level();
+    let mut outputs = OUTPUTS.lock().unwrap();
+    outputs.console = conf.console || to_console;
+    outputs.file = conf.file.clone();
+    outputs.max_size = conf.max_size;
+    outputs.keep = conf.keep;
+    outputs.syslog = if conf.syslog { connect_syslog() } else { None };
+    for (level, target, message) in outputs.pending.take().unwrap_or_default() {
+        if level <= level_for(&target) {
+            write(&mut outputs, level, &message);
+        }
+    }
+}
+
+// "<level>" for the default or "<module>=<level>", "<module>=default" drops the module's own level
+pub fn set_level(spec: &str) -> Result<(), String> {
+    let spec = spec.trim();
+    {
+        let mut filters = FILTERS.write().unwrap();
+        match spec.find('=') {
+            None => filters.default = parse_level(spec)?,
+            Some(i) => {
+                let module = spec[..i].trim().to_string();
+                let level = match spec[i + 1..].trim() {
+                    "default" => None,
+                    l => Some(parse_level(l)?),
+                };
+                filters.modules.retain(|(m, _)| *m != module);
+                if let Some(level) = level {
+                    filters.modules.push((module, level));
+                }
+            }
+        }
+    }
+    update_max_level();
+    log::info!("log level {}", spec);
+    return Ok(());
+}
+
+// The default level and any module levels, as set_level takes them
+pub fn levels() -> Vec<String> {
+    let filters = FILTERS.read().unwrap();
+    let mut levels = vec![filters.default.to_string().to_lowercase()];
+    for (module, level) in &filters.modules {
+        levels.push(format!("{}={}", module, level.to_string().to_lowercase()));
+    }
+    return levels;
+}
+
+pub fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    return LevelFilter::from_str(s).map_err(|_| format!("unknown log level {:?}, expected off, error, warn, info, debug or trace", s));
+}
+
+fn level_for(target: &str) -> LevelFilter {
+    let filters = FILTERS.read().unwrap();
+    let mut best: Option<(usize, LevelFilter)> = None;
+    for (module, level) in &filters.modules {
+        let own = format!("windyble::{}", module);
+        for prefix in &[module.as_str(), own.as_str()] {
+            let matches = target == *prefix || target.starts_with(&format!("{}::", prefix));
+            if matches && best.is_none_or(|(len, _)| prefix.len() > len) {
+                best = Some((prefix.len(), *level));
+            }
+        }
+    }
+    return best.map_or(filters.default, |(_, level)| level);
+}
+
+// log checks this before calling the logger at all, so it has to cover the most verbose module
+fn update_max_level() {
+    let filters = FILTERS.read().unwrap();
+    let max = filters.modules.iter().map(|(_, l)| *l).fold(filters.default, |a, b| a.max(b));
+    log::set_max_level(max);
+}
+
+fn connect_syslog() -> Option<UnixDatagram> {
+    let socket = UnixDatagram::unbound().ok()?;
+    return match socket.connect(SYSLOG_SOCKET) {
+        Ok(()) => Some(socket),
+        Err(e) => {
+            eprintln!("Failed to connect to syslog at {}: {:?}", SYSLOG_SOCKET, e);
+            None
+        }
+    };
+}
+
+fn syslog_priority(level: Level) -> u8 {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    return SYSLOG_FACILITY * 8 + severity;
+}
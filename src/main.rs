@@ -1,37 +1,90 @@
-use std::{env, fs, thread};
-use std::sync::{Condvar, Mutex};
+use std::{env, fs, process, thread};
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::ops::RangeInclusive;
+use std::panic::AssertUnwindSafe;
 use std::time::Duration;
 
+use async_std::future::timeout;
 use async_std::sync::Arc;
-use futures::executor::block_on;
+use async_std::task;
+use futures::future::{pending, select};
+use futures::FutureExt;
+use hive::handler::Handler;
 use hive::hive::Hive;
 use local_ipaddress;
 #[allow(unused_imports)]
-use log::{debug, error, info, Level, LevelFilter, Metadata, Record, SetLoggerError};
+use log::{debug, error, info, warn};
 #[cfg(target_arch = "arm")]
 use rppal::gpio::Gpio;
 #[cfg(target_arch = "arm")]
 use rppal::gpio::Level::High;
 use simple_signal::{self, Signal};
+use toml::Value;
 
 #[cfg(not(target_arch = "arm"))]
 use crate::mock_gpio::Gpio;
 #[cfg(not(target_arch = "arm"))]
 use crate::mock_gpio::Level::High;
-use crate::motor::Motor;
-use std::path::Path;
-
+use crate::motor::{Distance, Motor};
+use crate::schedule::Schedule;
+use crate::config::{Config, HiveRole};
+use crate::controller::Controller;
+use crate::error::WindybleError;
+use crate::events::Event;
+use crate::fault::Fault;
+use crate::gesture::{Button, Gestures};
+use crate::journal::Source;
+use std::path::{Path, PathBuf};
+
+mod api;
+mod config;
+mod control;
+mod controller;
+mod error;
+mod events;
+mod fault;
+mod gesture;
+mod journal;
+mod lockout;
+mod logging;
+mod metrics;
 mod motor;
+mod rolling;
+mod schedule;
+mod state;
+mod status;
+mod sun;
+mod supervisor;
+mod systemd;
+mod mqtt;
+mod websocket;
 #[cfg(not(target_arch = "arm"))]
 mod mock_gpio;
 
 
+/*
+  How the motor gets switched on and off: the 12 V supply relay, the stepper driver's
+  ENABLE input, or both (relay first on the way up, driver first on the way down)
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerMode {
+    Relay,
+    Enable,
+    Both,
+}
+
 #[derive(Clone, Copy)]
 pub struct GpioConfig {
     step: u8,
     dir: u8,
     power_relay_pin: u8,
+    power_mode: PowerMode,
+    // true when driving the relay pin high closes the relay
+    relay_active_high: bool,
+    enable_pin: Option<u8>,
+    // the A4988/DRV8825 ENABLE input is active low
+    enable_active_high: bool,
     pt1: u8,
     pt2: u8,
     is_up_pin: Option<u8>,
@@ -45,6 +98,10 @@ pub const GPIO_CONF: GpioConfig = GpioConfig {
     dir: 9,
     // enable motor pin
     power_relay_pin: 16,//10,
+    power_mode: PowerMode::Relay,
+    relay_active_high: false,
+    enable_pin: None,
+    enable_active_high: false,
     pt1: 6,
     pt2: 5,
     is_up_pin: Some(2),
@@ -107,37 +164,6 @@ impl From<i8> for MotorTurnState {
 //     go_down_pin: None, //Some(11)
 // };
 
-// init logging
-pub struct SimpleLogger;
-
-impl log::Log for SimpleLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Debug
-    }
-
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!("{:?} - {}", record.level(), record.args());
-        }
-    }
-
-    fn flush(&self) {}
-}
-
-pub static LOGGER: SimpleLogger = SimpleLogger;
-
-fn init_logging(to_console: bool) -> Result<(), SetLoggerError> {
-    if to_console {
-        log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Debug))
-            .expect("failed to init logger");
-    } else {
-        log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
-    }
-
-    Ok(())
-}
-
-
 struct PinDir;
 
 impl PinDir {
@@ -158,19 +184,49 @@ impl MoveState {
 
 static CURRENT_DIRECTION: AtomicU8 = AtomicU8::new(0);
 static CURRENT_MOVE_STATE: AtomicU8 = AtomicU8::new(MoveState::FREE);
+// set once we've been told to stop, the motor refuses new moves from then on
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+// how long the orderly shutdown gets before the power is cut and we exit anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub fn store_direction(d: u8) {
-    CURRENT_DIRECTION.store(d, Ordering::Relaxed)
+    if CURRENT_DIRECTION.swap(d, Ordering::Relaxed) != d {
+        events::publish(Event::Direction(d));
+    }
 }
 
 pub fn current_direction() -> u8 {
     CURRENT_DIRECTION.load(Ordering::Relaxed)
 }
 
+pub fn current_move_state() -> u8 {
+    CURRENT_MOVE_STATE.load(Ordering::SeqCst)
+}
+
+pub fn store_move_state(state: u8) {
+    if CURRENT_MOVE_STATE.swap(state, Ordering::SeqCst) != state {
+        events::publish(Event::MoveState(state));
+    }
+}
+
+pub fn shutting_down() -> bool {
+    return SHUTTING_DOWN.load(Ordering::SeqCst);
+}
+
+// True when the limit switch for the direction we'd be moving in is already closed
+pub fn at_limit(dir: u8) -> bool {
+    let state = CURRENT_MOVE_STATE.load(Ordering::SeqCst);
+    return match dir {
+        PinDir::COUNTER_CLOCKWISE => state == MoveState::UP,
+        _ => state == MoveState::DOWN,
+    };
+}
+
 #[allow(dead_code)]
 fn main_test() {
-    init_logging(true).expect("Failed to Init logger");
-    start_input_listener(6, move |v| {
+    logging::init(true).expect("Failed to Init logger");
+    logging::configure(&Config::from_str("").logging, true);
+    start_input_listener("test pin", 6, move |v| {
         println!("VAL {:?} is {:?}", 6, v);
     });
 
@@ -198,6 +254,10 @@ fn main_test() {
 ///     windyble test listen 3000
 ///     windyble test connect 192.168.0.43:3000
 /// ```
+///
+/// `windyble jog <distance>` moves a fixed distance and exits without joining the hive,
+/// the distance is in steps or mm, negative goes up: `windyble jog -200`, `windyble jog 15mm`.
+/// mm goes by the travel saved in the [state] file, which is put back afterwards
 fn main() {
     /*
     pt is 0,1,2,3 potentiometer limiting for the motor 0.5 A, 1 A, 1.5 A, 2 A
@@ -206,148 +266,163 @@ fn main() {
     const INIT_PT: i64 = 2;
     let args: Vec<String> = env::args().collect();
     let to_console = args.contains(&String::from("console"));
-    init_logging(to_console).expect("Failed to Init logger");
+    logging::init(to_console).expect("Failed to Init logger");
     let is_test = args.contains(&String::from("test"));
-    let addr = local_ipaddress::get().unwrap();
-    let props_file_name = args.get(args.len() - 1);
-    let path = Path::new(props_file_name.unwrap());
-    let hive_properties = if path.is_file() && !path.to_str().unwrap().contains("windyble") {
+    let cli_jog: Option<Distance> = args.iter().position(|a| a == "jog")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|d| Distance::parse(d));
+    let addr = local_ipaddress::get().unwrap_or_else(|| {
+        warn!("No local ip address found, using 127.0.0.1");
+        String::from("127.0.0.1")
+    });
+    let props_file_name = args.last().cloned().unwrap_or_default();
+    let path = Path::new(&props_file_name);
+    let (hive_properties, config_dir) = if path.is_file() && !path.to_string_lossy().contains("windyble") {
         debug!("found toml file {:?}", path);
-        fs::read_to_string(path)
+        (fs::read_to_string(path), path.parent().map(Path::to_path_buf).unwrap_or_default())
     } else {
         debug!("using default hive.toml");
-        fs::read_to_string("hive.toml")
+        (fs::read_to_string("hive.toml"), PathBuf::new())
     };
     let properties: String = match hive_properties {
         Ok(p) => {
             p.replace("(address)", &addr)
         }
         _ => {
-            error!("Failed to read hive properties file {:?}", props_file_name);
+            error::report(&WindybleError::Config(format!("can't read the properties file {:?}, using the defaults", props_file_name)));
             format!("listen = \"{}:3000\"
             [Properties]
-            turn: 0
+            turn = 0
             speed = {}
-            pt = {}", addr, motor::DEFAULT_DURATION, INIT_PT)
+            pt = {}
+            jog = 0
+            override = 0", addr, motor::DEFAULT_SPEED, INIT_PT)
         }
     };
 
     debug!("{}", properties);
-    let mut pi_hive = Hive::new_from_str("LEFT", properties.as_str());
-    let is_client: bool = !pi_hive.is_sever();
-
-    let motor: Motor = Motor::new(GPIO_CONF, is_test);
-
-    let turn_motor = move |direction: Option<u8>, do_turn: &(Mutex<bool>, Condvar)| {
-        let (lock, cvar) = do_turn;
-        // TODO PoisonError
-        let mut turning = lock.lock().unwrap();
-        let current_state = CURRENT_MOVE_STATE.load(Ordering::SeqCst);
-        match direction {
-            Some(PinDir::COUNTER_CLOCKWISE) => {
-                if current_state == MoveState::UP {
-                    info!("Already UP!!");
-                } else {
-                    store_direction(PinDir::COUNTER_CLOCKWISE);
-                    *turning = true;
-                }
-            }
-            Some(PinDir::CLOCKWISE) => {
-                if current_state == MoveState::DOWN {
-                    info!("Already DOWN!!");
-                } else {
-                    store_direction(PinDir::CLOCKWISE);
-                    *turning = true;
-                }
-            }
-            _ => {
-                *turning = false;
-            }
+    let mut config = Config::from_str(properties.as_str());
+    // relative log, state, schedule and journal files go next to the config file, wherever we were started from
+    let config_dir = fs::canonicalize(&config_dir).unwrap_or(config_dir);
+    let files = config.logging.file.iter_mut()
+        .chain(config.state_file.iter_mut())
+        .chain(config.schedule.file.iter_mut())
+        .chain(config.journal.file.iter_mut());
+    for file in files {
+        if Path::new(file.as_str()).is_relative() {
+            *file = config_dir.join(file.as_str()).to_string_lossy().to_string();
         }
-        cvar.notify_one();
+    }
+    logging::configure(&config.logging, to_console);
+    journal::init(config.journal.clone());
+    let motor: Motor = match Motor::new(config.gpio, config.motor, is_test) {
+        Ok(m) => m,
+        Err(e) => fatal(e),
     };
+    supervisor::watch(motor.clone());
+
+    // property names from here on go through names.property, they may have a prefix on the hive
+    let names = config.hive.clone();
+    let schedule = Schedule::new(&config.schedule.entries, config.schedule.file.clone(), config.location);
+    // the control properties the hive gets whether or not [Properties] has them, then the status
+    let mut extra: Vec<(&str, Value)> = vec![
+        ("preset", Value::from("")),
+        ("scene", Value::from("")),
+        ("schedule", Value::from(schedule.list().join(";"))),
+        ("log_level", Value::from("")),
+    ];
+    extra.extend(status::current(&motor));
+    let client_property = status::client_property(&names);
+    extra.push((client_property.as_str(), Value::from("")));
+    let hive_properties = names.properties(properties.as_str(), &extra);
+    let mut pi_hive = Hive::new_from_str(names.name.as_str(), hive_properties.as_str());
+    let is_client: bool = match names.role {
+        Some(role) => role == HiveRole::Client,
+        None => !pi_hive.is_sever(),
+    };
+    info!("hive node {} as {}", names.name, if is_client { "client" } else { "server" });
+    if !is_client {
+        status::guard(&mut pi_hive, &names);
+    }
 
-    let up_pair: Arc<(Mutex<bool>, Condvar)> = Arc::new((Mutex::new(false), Condvar::new()));
-    let speed_pair: Arc<(Mutex<i64>, Condvar)> = Arc::new((Mutex::new(0), Condvar::new()));
-    let pt_val_pair: Arc<(Mutex<i64>, Condvar)> = Arc::new((Mutex::new(0), Condvar::new()));
     let go_direction: Arc<Mutex<MotorTurnState>> = Arc::new(Mutex::new(MotorTurnState::Stopped));
+    let (controller, commands) = Controller::new(motor.clone(), schedule.clone(), config.presets.clone());
 
-    let gpio_conf: GpioConfig = GPIO_CONF;
-    if gpio_conf.is_up_pin.is_some() {
-        start_input_listener(gpio_conf.is_up_pin.unwrap(), {
-            let up_pair_clone = up_pair.clone();
+    let gpio_conf: GpioConfig = config.gpio;
+    /*
+        The limit switches go to the command loop like everything else, but the move state is
+        stored straight away as well, the step thread checks it before every step and shouldn't
+        have to wait for the loop to catch up
+     */
+    if let Some(pin) = gpio_conf.is_up_pin {
+        start_input_listener("top limit switch", pin, {
+            let controller = controller.clone();
             move |v| {
-                debug!("VAL {:?} is {:?}", gpio_conf.is_up_pin, v);
-                let (lock, cvar) = &*up_pair_clone;
-                let mut going_up = lock.lock().unwrap();
-                if v == 0 {
-                    // Reached the top stop
-                    CURRENT_MOVE_STATE.store(MoveState::UP, Ordering::SeqCst);
-                    *going_up = false;
-                } else {
-                    CURRENT_MOVE_STATE.store(MoveState::FREE, Ordering::SeqCst);
-                }
-                cvar.notify_one();
+                debug!("VAL {:?} is {:?}", pin, v);
+                store_move_state(if v == 0 { MoveState::UP } else { MoveState::FREE });
+                controller.limit_reached(true, v == 0);
             }
         });
     }
 
-    if gpio_conf.is_down_pin.is_some() {
-        start_input_listener(gpio_conf.is_down_pin.unwrap(), {
-            let up_pair_clone = up_pair.clone();
-            let pin_num = gpio_conf.is_down_pin.unwrap().clone();
+    if let Some(pin) = gpio_conf.is_down_pin {
+        start_input_listener("bottom limit switch", pin, {
+            let controller = controller.clone();
             move |v| {
-                debug!("VAL {:?} is {:?}", pin_num, v);
-                let (lock, cvar) = &*up_pair_clone;
-                let mut going_down = lock.lock().unwrap();
-                if v == 0 {
-                    // Reached the bottom stop
-                    CURRENT_MOVE_STATE.store(MoveState::DOWN, Ordering::SeqCst);
-                    *going_down = false;
-                } else {
-                    CURRENT_MOVE_STATE.store(MoveState::FREE, Ordering::SeqCst);
-                }
-                cvar.notify_one();
+                debug!("VAL {:?} is {:?}", pin, v);
+                store_move_state(if v == 0 { MoveState::DOWN } else { MoveState::FREE });
+                controller.limit_reached(false, v == 0);
             }
         });
     }
 
-    if gpio_conf.go_up_pin.is_some() {
-        start_input_listener(gpio_conf.go_up_pin.unwrap(), {
-            let up_pair2: Arc<(Mutex<bool>, Condvar)> = up_pair.clone();
+    /*
+        The go buttons are read as gestures: press moves straight away, a tap carries on to
+        the end stop, holding moves until release, a double tap goes to the favourite position
+        (a number or a preset, resolved when the config is read), a press while moving stops
+        and both buttons together stop, or calibrate when held. Any of them puts the blind in manual override for a while,
+        see src/lockout.rs. The command loop in src/controller.rs acts on them
+     */
+    let gestures = Gestures::new(config.buttons, {
+        let motor = motor.clone();
+        move || motor.is_running()
+    }, {
+        let controller = controller.clone();
+        move |gesture| controller.button(gesture)
+    });
+
+    if let Some(pin) = gpio_conf.go_up_pin {
+        start_input_listener("up button", pin, {
+            let gestures = gestures.clone();
             move |v| {
                 debug!("GO UP PIN: {:?}", v);
-                if v == 1 {
-                    &turn_motor(Some(PinDir::COUNTER_CLOCKWISE), &*up_pair2);
-                } else {
-                    &turn_motor(None, &*up_pair2);
-                }
+                gestures.input(Button::Up, v == 1);
             }
         });
     }
 
-    if gpio_conf.go_down_pin.is_some() {
-        start_input_listener(gpio_conf.go_down_pin.unwrap(), {
-            let up_pair2: Arc<(Mutex<bool>, Condvar)> = up_pair.clone();
+    if let Some(pin) = gpio_conf.go_down_pin {
+        start_input_listener("down button", pin, {
+            let gestures = gestures.clone();
             move |v| {
                 debug!("GO DOWN PIN: {:?}", v);
-                if v == 1 {
-                    &turn_motor(Some(PinDir::CLOCKWISE), &*up_pair2);
-                } else {
-                    &turn_motor(None, &*up_pair2);
-                }
+                gestures.input(Button::Down, v == 1);
             }
         });
     }
 
 
-    let pt_val_clone: Arc<(Mutex<i64>, Condvar)> = pt_val_pair.clone();
-    pi_hive.get_mut_property("pt").unwrap().on_changed.connect(move |value| {
-        let (lock, cvar) = &*pt_val_clone;
-        let mut pt = lock.lock().unwrap();
-        *pt = value.unwrap().as_integer().unwrap();
-        cvar.notify_one();
-    });
+    let controller_pt = controller.clone();
+    match pi_hive.get_mut_property(&names.property("pt")) {
+        Some(pt) => pt.on_changed.connect(move |value| {
+            hive_message();
+            match int_value("pt", value, 0..=3) {
+                Ok(pt) => controller_pt.set_pt(pt),
+                Err(e) => error::report(&e),
+            }
+        }),
+        None => error::report(&missing_property(&names.property("pt"))),
+    }
 
     /*
         moveup and movedown are a ready go flag, 2 means, stopped, 1 means power up and get ready
@@ -358,42 +433,254 @@ fn main() {
     let pi_have_handle = pi_hive.get_handler();
 
 
-    pi_hive.get_mut_property("turn").unwrap().on_changed.connect({
-        let up_pair2: Arc<(Mutex<bool>, Condvar)> = up_pair.clone();
-        let motor_clone = motor.clone();
-
-        move |value| {
-            let do_go_up = value.unwrap().as_integer().unwrap() as i8;
-            if do_go_up == MotorTurnState::ReadyDown || do_go_up == MotorTurnState::ReadyUp { // Ready
-                debug!("power up!");
-                motor_clone.power_motor(true);
-                *go_direction.lock().unwrap() = do_go_up.into();
-
-                if is_client {
-                    block_on(
-                        pi_have_handle.clone()
-                            .send_property_value("turn",
-                                                 Some(&MotorTurnState::Go.value().into()),
-                            )
-                    );
+    match pi_hive.get_mut_property(&names.property("turn")) {
+        Some(turn) => turn.on_changed.connect({
+            let controller = controller.clone();
+            let turn_name = names.property("turn");
+
+            move |value| {
+                hive_message();
+                let do_go_up = match int_value("turn", value, 0..=3) {
+                    Ok(v) => v as i8,
+                    Err(e) => return error::report(&e),
+                };
+                events::publish(Event::Turn(do_go_up));
+                if do_go_up != MotorTurnState::Stopped {
+                    if let Err(e) = lockout::check() {
+                        info!("turn {} ignored, {}", do_go_up, e);
+                        return;
+                    }
+                }
+                if do_go_up == MotorTurnState::ReadyDown || do_go_up == MotorTurnState::ReadyUp { // Ready
+                    debug!("power up!");
+                    // a client's driver is bridged, the server does the stepping
+                    controller.power(true, is_client);
+                    *go_direction.lock().unwrap() = do_go_up.into();
+
+                    if is_client {
+                        let mut handle = pi_have_handle.clone();
+                        let turn_name = turn_name.clone();
+                        task::spawn(async move {
+                            handle.send_property_value(&turn_name, Some(&MotorTurnState::Go.value().into())).await;
+                        });
+                    }
+                } else if do_go_up == MotorTurnState::Go && !is_client { // GO
+                    if !is_client {
+                        let direction = match *go_direction.lock().unwrap() {
+                            MotorTurnState::ReadyUp => PinDir::COUNTER_CLOCKWISE,
+                            _ => PinDir::CLOCKWISE
+                        };
+                        controller.turn(Some(direction), Source::Hive);
+                    }
+                } else if do_go_up == MotorTurnState::Stopped {
+                    // STOP
+                    // the client doesn't power itself off because only the server steps
+                    controller.stop();
+                }
+            }
+        }),
+        None => error::report(&missing_property(&names.property("turn"))),
+    }
+
+    /*
+        jog is a distance in steps (or a string like "15mm"), positive is down. Like turn only the
+        server steps, clients power up for it and power down when the server resets jog to 0
+     */
+    let jog_handle = pi_hive.get_handler();
+    if let Some(jog) = pi_hive.get_mut_property(&names.property("jog")) {
+        jog.on_changed.connect({
+            let controller = controller.clone();
+            let motor_clone = motor.clone();
+            let jog_name = names.property("jog");
+            move |value| {
+                hive_message();
+                let distance = match value {
+                    None => None,
+                    Some(v) => match v.as_integer().map(Distance::Steps).or_else(|| v.as_str().and_then(Distance::parse)) {
+                        Some(d) => Some(d),
+                        None => {
+                            return error::report(&WindybleError::Protocol(format!("jog should be steps or a distance like \"15mm\", got {}", v)));
+                        }
+                    },
+                };
+                match distance {
+                    None | Some(Distance::Steps(0)) | Some(Distance::Mm(0)) => {
+                        if is_client {
+                            controller.stop();
+                        }
+                    }
+                    Some(d) if lockout::check().is_err() => {
+                        info!("jog {:?} ignored during a manual override", d);
+                    }
+                    Some(d) if is_client => {
+                        debug!("power up for jog {:?}", d);
+                        controller.power(true, true);
+                    }
+                    Some(d) => {
+                        // the loop runs the jog, reset jog once it's done or was refused so clients power down
+                        let started = controller.jog(d, Source::Hive);
+                        let motor_clone = motor_clone.clone();
+                        let mut handle = jog_handle.clone();
+                        let jog_name = jog_name.clone();
+                        task::spawn(async move {
+                            if let Some(started) = started {
+                                if started.recv().await == Ok(true) {
+                                    motor_clone.stopped().await;
+                                }
+                            }
+                            handle.send_property_value(&jog_name, Some(&0.into())).await;
+                        });
+                    }
+                }
+            }
+        });
+    }
+
+    /*
+        preset is the name of one of the [presets]. Like jog only the server moves, clients power
+        up for it and power down when the server resets preset to "" once the move is done
+     */
+    let preset_handle = pi_hive.get_handler();
+    match pi_hive.get_mut_property(&names.property("preset")) {
+        Some(preset) => preset.on_changed.connect({
+            let controller = controller.clone();
+            let preset_name = names.property("preset");
+            move |value| {
+                hive_message();
+                let name = match str_value("preset", value) {
+                    Ok(name) => name,
+                    Err(e) => return error::report(&e),
+                };
+                if name.is_empty() {
+                    if is_client {
+                        controller.stop();
+                    }
+                } else if is_client {
+                    if lockout::check().is_ok() {
+                        debug!("power up for preset {}", name);
+                        controller.power(true, true);
+                    }
+                } else {
+                    let moving = lockout::check().and_then(|_| controller.go_to_preset(name, Source::Hive));
+                    let motor = controller.motor().clone();
+                    let mut handle = preset_handle.clone();
+                    let preset_name = preset_name.clone();
+                    task::spawn(async move {
+                        match moving {
+                            Ok(started) => {
+                                if started.recv().await == Ok(true) {
+                                    motor.stopped().await;
+                                }
+                            }
+                            Err(e) => error::report(&WindybleError::Protocol(format!("can't go to preset: {}", e))),
+                        }
+                        handle.send_property_value(&preset_name, Some(&"".into())).await;
+                    });
+                }
+            }
+        }),
+        None => error::report(&missing_property(&names.property("preset"))),
+    }
+
+    /*
+        scene moves every node to its own preset for the scene, from [scenes], nodes without it
+        stay put. Meant for hives where each node has its own blind rather than bridged drivers.
+        The server clears it again so the same scene can be picked twice in a row.
+     */
+    let scene_handle = pi_hive.get_handler();
+    match pi_hive.get_mut_property(&names.property("scene")) {
+        Some(scene) => scene.on_changed.connect({
+            let controller = controller.clone();
+            let scene_name = names.property("scene");
+            move |value| {
+                hive_message();
+                let name = match str_value("scene", value) {
+                    Ok(name) => name,
+                    Err(e) => return error::report(&e),
+                };
+                if name.is_empty() {
+                    return;
+                }
+                match lockout::check().and_then(|_| controller.scene(name, Source::Hive)) {
+                    Ok(true) => {}
+                    Ok(false) => debug!("not in scene {}", name),
+                    Err(e) => error::report(&WindybleError::Protocol(format!("can't set scene {}: {}", name, e))),
                 }
-            } else if do_go_up == MotorTurnState::Go && !is_client { // GO
                 if !is_client {
-                    let direction = match *go_direction.lock().unwrap() {
-                        MotorTurnState::ReadyUp => PinDir::COUNTER_CLOCKWISE,
-                        _ => PinDir::CLOCKWISE
-                    };
-                    &turn_motor(Some(direction), &*up_pair2);
+                    let mut handle = scene_handle.clone();
+                    let scene_name = scene_name.clone();
+                    task::spawn(async move {
+                        handle.send_property_value(&scene_name, Some(&"".into())).await;
+                    });
                 }
-            } else if do_go_up == MotorTurnState::Stopped {
-                // STOP
-                // the motor turns itself off
-                &turn_motor(None, &*up_pair2);
-                // the client doesn't power itself off because its out of the run/norun loop
-                motor_clone.power_motor(false);
             }
-        }
-    });
+        }),
+        None => error::report(&missing_property(&names.property("scene"))),
+    }
+
+    /*
+        override is when the manual override ends, in seconds since the unix epoch, so a button
+        press on any node holds off automation on all of them. Setting it to 0 resumes automation.
+        Local changes are sent on from the event stream, a node receiving its own value back
+        doesn't see a change and stops there.
+     */
+    let override_handle = pi_hive.get_handler();
+    if let Some(override_prop) = pi_hive.get_mut_property(&names.property("override")) {
+        override_prop.on_changed.connect(move |value| {
+            hive_message();
+            match value.map_or(Ok(0), |_| int_value("override", value, 0..=i64::MAX)) {
+                Ok(until) => lockout::set_until(until as u64),
+                Err(e) => error::report(&e),
+            }
+        });
+        let events = events::subscribe_async();
+        let override_name = names.property("override");
+        task::spawn(async move {
+            let mut handle = override_handle;
+            while let Ok(envelope) = events.recv().await {
+                if let Event::Override(until) = envelope.event {
+                    let until = until.unwrap_or(0) as i64;
+                    handle.send_property_value(&override_name, Some(&until.into())).await;
+                }
+            }
+        });
+    }
+
+    // log_level takes the same "debug" or "motor=trace" as windyble-ctl log-level, see src/logging.rs
+    match pi_hive.get_mut_property(&names.property("log_level")) {
+        Some(log_level) => log_level.on_changed.connect(move |value| {
+            hive_message();
+            match str_value("log_level", value) {
+                Ok("") => {}
+                Ok(spec) => {
+                    if let Err(e) = logging::set_level(spec) {
+                        error::report(&WindybleError::Protocol(format!("rejected log_level: {}", e)));
+                    }
+                }
+                Err(e) => error::report(&e),
+            }
+        }),
+        None => error::report(&missing_property(&names.property("log_level"))),
+    }
+
+    // schedule is the whole list of entries separated by ';', see src/schedule.rs
+    match pi_hive.get_mut_property(&names.property("schedule")) {
+        Some(schedule_prop) => schedule_prop.on_changed.connect({
+            let schedule = schedule.clone();
+            move |value| {
+                hive_message();
+                let lines: Vec<String> = match str_value("schedule", value) {
+                    Ok("") => vec![],
+                    Ok(s) => s.split(';').map(String::from).collect(),
+                    Err(e) => return error::report(&e),
+                };
+                if let Err(e) = schedule.set_all(&lines) {
+                    error::report(&WindybleError::Protocol(format!("rejected schedule: {}", e)));
+                }
+            }
+        }),
+        None => error::report(&missing_property(&names.property("schedule"))),
+    }
 
     // let up_pair2: Arc<(Mutex<bool>, Condvar)> = up_pair.clone();
     // pi_hive.get_mut_property("movedown").unwrap().on_changed.connect(move |value| {
@@ -402,54 +689,53 @@ fn main() {
     //     turn_motor(dir, &*up_pair2);
     // });
 
-    let speed_clone = speed_pair.clone();
-    pi_hive.get_mut_property("speed").unwrap().on_changed.connect(move |value| {
-        let (lock, cvar) = &*speed_clone;
-        let mut speed = lock.lock().unwrap();
-        *speed = value.unwrap().as_integer().unwrap();
-        cvar.notify_one();
-    });
+    let controller_speed = controller.clone();
+    match pi_hive.get_mut_property(&names.property("speed")) {
+        Some(speed) => speed.on_changed.connect(move |value| {
+            hive_message();
+            match int_value("speed", value, 0..=100) {
+                Ok(speed) => controller_speed.set_speed(speed),
+                Err(e) => error::report(&e),
+            }
+        }),
+        None => error::report(&missing_property(&names.property("speed"))),
+    }
 
     /*
      The derived_pt is the value that was passed in via the toml text file
      which we use for initializing the motor
      */
-    let derived_pt: i64 = pi_hive.properties.get("pt").unwrap()
-        .value.as_ref()
-        .unwrap()
-        .as_integer()
-        .unwrap();
-    thread::spawn(move || {
-        info!("run Hive");
-        block_on(pi_hive.run());
-    });
-
-    motor.init(&derived_pt);
-
-    // Handler for potentiometer
-    // todo task::spawn here doesn't work.. figure out why
-    let motor_clone3 = motor.clone();
-    thread::spawn(move || {
-        let (lock, cvar) = &*pt_val_pair;
-        let mut pt = lock.lock().unwrap();
-        loop {
-            pt = cvar.wait(pt).unwrap();
-            motor_clone3.set_potentiometer(&*pt);
+    let derived_pt: i64 = match int_value("pt", pi_hive.properties.get(&names.property("pt")).and_then(|p| p.value.as_ref()), 0..=3) {
+        Ok(pt) => pt,
+        Err(e) => {
+            error::report(&WindybleError::Config(format!("{}, starting at {}", e.message(), INIT_PT)));
+            INIT_PT
         }
-    });
+    };
 
-    // Handler for speed
-    // todo task::spawn here doesn't work.. figure out why
-    let motor_clone2 = motor.clone();
-    thread::spawn(move || {
-        let (lock, cvar) = &*speed_pair;
-        let mut speed = lock.lock().unwrap();
-        loop {
-            speed = cvar.wait(speed).unwrap();
-            motor_clone2.set_speed(*speed as u64);
-        };
-    });
+    motor.init(&derived_pt);
+    state::restore(&config.state_file, &motor);
+
+    if let Some(distance) = cli_jog {
+        info!("jog {:?}", distance);
+        motor.jog_and_wait(distance, Source::Cli);
+        // restoring took the file, it goes back with where the jog left the motor
+        state::save(&config.state_file, &motor);
+        return;
+    }
 
+    let shutdown_handle = pi_hive.get_handler();
+    controller::run(controller.clone(), commands, config.buttons);
+    api::start(config.http, controller.clone());
+    mqtt::start(config.mqtt.clone(), controller.clone());
+    websocket::start(config.http.ws_port, controller.clone());
+    control::start(config.control_socket.clone(), controller.clone());
+    schedule.start(controller.clone());
+    if is_client {
+        status::handshake(names.clone(), pi_hive.get_handler());
+    } else {
+        status::start(names.clone(), pi_hive.get_handler(), motor.clone());
+    }
 
     let running = Arc::new(AtomicBool::new(true));
     simple_signal::set_handler(&[Signal::Int, Signal::Term], {
@@ -461,40 +747,111 @@ fn main() {
         }
     });
 
-    // Loops forever !!!
-    let motor_clone = motor.clone();
-    thread::spawn(move || {
-        let (lock, cvar) = &*up_pair;
-        let mut turning = lock.lock().unwrap();
-
-        while !*turning {
-            //we wait until we receive a turn message
-            println!("waiting to turn");
-            turning = cvar.wait(turning).unwrap();
-            if *turning {
-                let dir = current_direction();// CURRENT_DIRECTION.load(Ordering::SeqCst);
-                motor_clone.turn(dir);
-
-                while *turning {
-                    //we wait until we receive a stop turn message
-                    turning = cvar.wait(turning).unwrap();
-                    if !*turning {
-                        motor_clone.stop();
-                        break;
-                    }
-                }
+    /*
+        Everything from here is on the async-std runtime. The hive runs on this thread, the
+        command loop, input pins, timers and schedule are tasks, and the hive callbacks send
+        from tasks of their own rather than blocking the hive. The servers for the http api,
+        mqtt, websocket and control socket keep their threads, their libraries block.
+        The workers are all supervised, see src/supervisor.rs, a panic in one cuts the motor's
+        power and the worker is started again.
+        The main loop ends with the shutdown, which takes the hive down with it.
+     */
+    let hive = async move {
+        info!("run Hive");
+        metrics::hive_running(true);
+        // the hive owns its connections, there's no starting it again from here
+        if let Err(cause) = AssertUnwindSafe(pi_hive.run()).catch_unwind().await {
+            supervisor::give_up("hive", cause);
+        }
+        metrics::hive_running(false);
+        warn!("Hive stopped running");
+        // carry on without it until we're told to stop
+        pending::<()>().await;
+    };
+    let main_loop = async {
+        let mut watchdog = systemd::Watchdog::from_env();
+        while running.load(Ordering::SeqCst) {
+            // loop while were running
+            task::sleep(Duration::from_millis(100)).await;
+            watchdog.tick(&controller).await;
+        }
+        shutdown(&controller, &config, shutdown_handle).await;
+    };
+    task::block_on(select(Box::pin(hive), Box::pin(main_loop)));
+}
+
+/*
+    Shutting down: refuse new moves, slow any move to a stop and wait for the step thread,
+    leave the pins safe, save where the motor is and tell the hive the motor has stopped,
+    so bridged clients power down too. It gets SHUTDOWN_TIMEOUT, after that the power is
+    cut and we exit with an error.
+ */
+async fn shutdown(controller: &Controller, config: &Config, mut hive_handle: Handler) {
+    systemd::stopping();
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    info!("Shutting down");
+    let motor = controller.motor().clone();
+    let steps = {
+        let motor = motor.clone();
+        let state_file = config.state_file.clone();
+        let control_socket = config.control_socket.clone();
+        let turn_name = config.hive.property("turn");
+        async move {
+            let was_active = motor.is_running() || motor.is_powered();
+            controller.stop();
+            task::spawn_blocking(move || {
+                motor.stop_gently();
+                motor.done();
+                state::save(&state_file, &motor);
+                control::stop(&control_socket);
+            }).await;
+            if was_active {
+                debug!("telling the hive the motor stopped");
+                hive_handle.send_property_value(&turn_name, Some(&MotorTurnState::Stopped.value().into())).await;
             }
         }
-    });
+    };
+    match timeout(SHUTDOWN_TIMEOUT, steps).await {
+        Ok(()) => info!("Main Done"),
+        Err(_) => {
+            error!("Shutdown didn't finish in {:?}, cutting the motor power", SHUTDOWN_TIMEOUT);
+            motor.cut_power();
+            process::exit(1);
+        }
+    }
+}
 
-    while running.load(Ordering::SeqCst) {
-        // loop while were running
-        thread::sleep(Duration::from_millis(100))
+// Every property change from the hive comes through here
+fn hive_message() {
+    metrics::hive_message();
+}
+
+// Startup can't go on without it, logged before we exit
+fn fatal(e: WindybleError) -> ! {
+    error!("{}", e);
+    process::exit(1);
+}
+
+fn missing_property(name: &str) -> WindybleError {
+    return WindybleError::Hive(format!("no {} property in [Properties], it can't be set from the hive", name));
+}
+
+// Property values come from peers, anything that isn't a whole number in range is rejected
+fn int_value(name: &str, value: Option<&Value>, range: RangeInclusive<i64>) -> error::Result<i64> {
+    let value = value.ok_or_else(|| WindybleError::Protocol(format!("{} has no value", name)))?;
+    return match value.as_integer() {
+        Some(n) if range.contains(&n) => Ok(n),
+        Some(n) => Err(WindybleError::Protocol(format!("{} {} is outside {:?}", name, n, range))),
+        None => Err(WindybleError::Protocol(format!("{} should be a whole number, got {}", name, value))),
     };
+}
 
-    // Any cleanup needs to happen here
-    motor.done();
-    info!("Main Done");
+// A string property, no value is taken as ""
+fn str_value<'a>(name: &str, value: Option<&'a Value>) -> error::Result<&'a str> {
+    return match value {
+        None => Ok(""),
+        Some(v) => v.as_str().ok_or_else(|| WindybleError::Protocol(format!("{} should be a string, got {}", name, v))),
+    };
 }
 
 // #[allow(unused_variables)]
@@ -505,23 +862,34 @@ fn main() {
 
 
 // #[cfg(target_arch = "arm")]
-fn start_input_listener(num: u8, func: impl Fn(u8) + Send + Sync + 'static) {
-    thread::spawn(move || {
-        let gpio = Gpio::new().unwrap();
-        let mut pin = gpio.get(num).unwrap().into_input_pulldown();
-        pin.set_reset_on_drop(false);
-        let mut last_val = if pin.read() == High { 1 } else { 0 };
-
-        info!("Start listening to pin {}", num);
-        loop {
-            let new_val = if pin.read() == High { 1 } else { 0 };
-            if new_val != last_val {
-                println!("pin == {:?}", pin.read());
-                func(new_val);
-                last_val = new_val;
-            }
+fn start_input_listener(name: &'static str, num: u8, func: impl Fn(u8) + Send + Sync + 'static) {
+    let func = Arc::new(func);
+    supervisor::task(name, move || {
+        let func = func.clone();
+        async move {
+            let pin = Gpio::new().and_then(|gpio| gpio.get(num));
+            let mut pin = match pin {
+                Ok(p) => p.into_input_pulldown(),
+                Err(e) => {
+                    error::report(&WindybleError::gpio(num, name, e));
+                    fault::raise(Fault::Gpio);
+                    return;
+                }
+            };
+            pin.set_reset_on_drop(false);
+            let mut last_val = if pin.read() == High { 1 } else { 0 };
+
+            info!("Start listening to pin {} ({})", num, name);
+            loop {
+                let new_val = if pin.read() == High { 1 } else { 0 };
+                if new_val != last_val {
+                    println!("pin == {:?}", pin.read());
+                    func(new_val);
+                    last_val = new_val;
+                }
 
-            thread::sleep(Duration::from_millis(30))
-        };
+                task::sleep(Duration::from_millis(30)).await;
+            };
+        }
     });
 }
@@ -0,0 +1,141 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::controller::Controller;
+use crate::events;
+use crate::fault::Fault;
+use crate::PinDir;
+
+/*
+  Counters and gauges for prometheus, served in its text format from GET /metrics on the
+  http api. Counters start from zero on every restart, prometheus copes with the reset.
+
+  scrape_configs:
+    - job_name: windyble
+      static_configs:
+        - targets: ["windyble.local:8080"]
+ */
+static STEPS_UP: AtomicU64 = AtomicU64::new(0);
+static STEPS_DOWN: AtomicU64 = AtomicU64::new(0);
+static MOVES: AtomicU64 = AtomicU64::new(0);
+static RUN_MILLIS: AtomicU64 = AtomicU64::new(0);
+static LIMIT_TOP: AtomicU64 = AtomicU64::new(0);
+static LIMIT_BOTTOM: AtomicU64 = AtomicU64::new(0);
+// indexed by the fault's number, which starts at 1
+static FAULTS: [AtomicU64; Fault::ALL.len() + 1] = [const { AtomicU64::new(0) }; Fault::ALL.len() + 1];
+// finished power on periods, the current one is added when rendering
+static POWER_MILLIS: AtomicU64 = AtomicU64::new(0);
+static POWERED_SINCE: Mutex<Option<Instant>> = Mutex::new(None);
+static HIVE_RUNNING: AtomicBool = AtomicBool::new(false);
+// milliseconds since the unix epoch, 0 before the first one
+static HIVE_LAST_MESSAGE: AtomicU64 = AtomicU64::new(0);
+
+// A finished run of the step thread
+pub fn record_move(dir: u8, steps: u64, took: Duration) {
+    MOVES.fetch_add(1, Ordering::Relaxed);
+    RUN_MILLIS.fetch_add(took.as_millis() as u64, Ordering::Relaxed);
+    let counter = if dir == PinDir::COUNTER_CLOCKWISE { &STEPS_UP } else { &STEPS_DOWN };
+    counter.fetch_add(steps, Ordering::Relaxed);
+}
+
+pub fn limit_hit(top: bool) {
+    let counter = if top { &LIMIT_TOP } else { &LIMIT_BOTTOM };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn fault(fault: Fault) {
+    FAULTS[fault as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+// Called on every change of the motor power, repeats are ignored
+pub fn power(on: bool) {
+    let mut since = POWERED_SINCE.lock().unwrap();
+    match (on, *since) {
+        (true, None) => *since = Some(Instant::now()),
+        (false, Some(at)) => {
+            POWER_MILLIS.fetch_add(at.elapsed().as_millis() as u64, Ordering::Relaxed);
+            *since = None;
+        }
+        _ => {}
+    }
+}
+
+pub fn hive_running(running: bool) {
+    HIVE_RUNNING.store(running, Ordering::SeqCst);
+}
+
+// Any property change coming in from the hive
+pub fn hive_message() {
+    HIVE_LAST_MESSAGE.store(events::now_millis(), Ordering::Relaxed);
+}
+
+pub fn render(controller: &Controller) -> String {
+    let motor = controller.motor();
+    let mut out = String::new();
+
+    header(&mut out, "windyble_steps_total", "counter", "Steps taken, by direction");
+    sample(&mut out, "windyble_steps_total{direction=\"up\"}", STEPS_UP.load(Ordering::Relaxed) as f64);
+    sample(&mut out, "windyble_steps_total{direction=\"down\"}", STEPS_DOWN.load(Ordering::Relaxed) as f64);
+
+    header(&mut out, "windyble_moves_total", "counter", "Runs of the step thread");
+    sample(&mut out, "windyble_moves_total", MOVES.load(Ordering::Relaxed) as f64);
+
+    header(&mut out, "windyble_limit_hits_total", "counter", "Limit switches closing, by switch");
+    sample(&mut out, "windyble_limit_hits_total{switch=\"top\"}", LIMIT_TOP.load(Ordering::Relaxed) as f64);
+    sample(&mut out, "windyble_limit_hits_total{switch=\"bottom\"}", LIMIT_BOTTOM.load(Ordering::Relaxed) as f64);
+
+    let powered_for = POWERED_SINCE.lock().unwrap().map_or(0, |at| at.elapsed().as_millis() as u64);
+    header(&mut out, "windyble_power_on_seconds_total", "counter", "Time the motor power has been on");
+    sample(&mut out, "windyble_power_on_seconds_total", seconds(POWER_MILLIS.load(Ordering::Relaxed) + powered_for));
+
+    header(&mut out, "windyble_run_seconds_total", "counter", "Time spent stepping");
+    sample(&mut out, "windyble_run_seconds_total", seconds(RUN_MILLIS.load(Ordering::Relaxed)));
+
+    header(&mut out, "windyble_faults_total", "counter", "Faults raised, by kind");
+    for f in Fault::ALL.iter() {
+        let count = FAULTS[*f as usize].load(Ordering::Relaxed);
+        sample(&mut out, &format!("windyble_faults_total{{kind=\"{}\"}}", f.name()), count as f64);
+    }
+
+    header(&mut out, "windyble_speed_percent", "gauge", "Configured speed");
+    sample(&mut out, "windyble_speed_percent", controller.speed() as f64);
+
+    header(&mut out, "windyble_pt", "gauge", "Configured current limit, 0 (0.5 A) to 3 (2 A)");
+    sample(&mut out, "windyble_pt", motor.current_limit() as f64);
+
+    header(&mut out, "windyble_running", "gauge", "1 while stepping");
+    sample(&mut out, "windyble_running", flag(motor.is_running()));
+
+    header(&mut out, "windyble_powered", "gauge", "1 while the motor power is on");
+    sample(&mut out, "windyble_powered", flag(motor.is_powered()));
+
+    header(&mut out, "windyble_hive_running", "gauge", "1 while the hive connection is running");
+    sample(&mut out, "windyble_hive_running", flag(HIVE_RUNNING.load(Ordering::SeqCst)));
+
+    // left out until something has arrived, an age since start would read as a fresh message
+    let last = HIVE_LAST_MESSAGE.load(Ordering::Relaxed);
+    if last > 0 {
+        header(&mut out, "windyble_hive_last_message_age_seconds", "gauge", "Time since the last property change from the hive");
+        sample(&mut out, "windyble_hive_last_message_age_seconds", seconds(events::now_millis().saturating_sub(last)));
+    }
+    return out;
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, value: f64) {
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn seconds(millis: u64) -> f64 {
+    return millis as f64 / 1000.0;
+}
+
+fn flag(on: bool) -> f64 {
+    return if on { 1.0 } else { 0.0 };
+}
@@ -1,9 +1,13 @@
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::thread;
-use std::thread::sleep;
-use std::time::Duration;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
 
 use async_std::sync::Arc;
+use async_std::task;
 
 
 #[cfg(target_arch = "arm")]
@@ -13,16 +17,71 @@ use rppal::gpio::{Gpio, InputPin, OutputPin};
 use crate::mock_gpio::{Gpio, InputPin, OutputPin};
 
 #[allow(unused_imports)]
-use log::{info, warn, debug};
-use crate::{PinDir, GpioConfig};
+use log::{error, info, warn, debug};
+use crate::{PinDir, GpioConfig, PowerMode};
+use crate::config::{HoldMode, MotorConfig};
+use crate::events::{self, Event};
+use crate::error::{self, Result, WindybleError};
+use crate::fault::{self, Fault};
+use crate::journal::{self, Ended, Source};
+use crate::metrics;
+use crate::supervisor;
 
 #[derive(Clone)]
 pub struct Motor {
     gpio_config: GpioConfig,
+    motor_config: MotorConfig,
     running: Arc<AtomicBool>,
     step_duration: Arc<AtomicU64>,
     is_test: bool,
     gpio: Gpio,
+    // the current limit to drive at, while holding the pins are at the lowest setting instead
+    run_pt: Arc<AtomicI64>,
+    powered: Arc<AtomicBool>,
+    holding: Arc<AtomicBool>,
+    // powered for a move another node steps, see power_bridged
+    bridged: Arc<AtomicBool>,
+    // bumped on every power or step change so a pending hold timer knows it's stale
+    idle_gen: Arc<AtomicU64>,
+    // when the relay last switched on, stepping waits for the supply to settle from here
+    powered_at: Arc<Mutex<Option<Instant>>>,
+    // the step thread with the number of the move it's running
+    step_thread: Arc<Mutex<Option<StepThread>>>,
+    // moves started so far, a jog only cleans up after its own
+    moves: Arc<AtomicU64>,
+    // held while a move starts and while a stop waits for the step thread and powers off
+    finishing: Arc<Mutex<()>>,
+    // when the step thread last came round its loop, milliseconds since the unix epoch
+    beat: Arc<AtomicU64>,
+    // steps from the top stop, only meaningful once homed
+    position: Arc<AtomicI64>,
+    homed: Arc<AtomicBool>,
+    // steps from top to bottom, 0 until measured
+    travel: Arc<AtomicI64>,
+    // set by stop_gently, the step thread slows down over a few steps and then ends
+    decelerate: Arc<AtomicBool>,
+    // set by cut_power, the move it ends goes in the journal as ended by the watchdog
+    cut: Arc<AtomicBool>,
+}
+
+/*
+  A relative move for jog, parsed from "200", "-200", "15mm" or "-15mm"
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distance {
+    Steps(i64),
+    Mm(i64),
+}
+
+impl Distance {
+    pub fn parse(s: &str) -> Option<Distance> {
+        let s = s.trim();
+        return if s.ends_with("mm") {
+            s.trim_end_matches("mm").trim().parse::<i64>().ok().map(Distance::Mm)
+        } else {
+            s.parse::<i64>().ok().map(Distance::Steps)
+        };
+    }
 }
 
 // impl Clone for Motor {
@@ -39,7 +98,17 @@ pub struct Motor {
 
 const SPEED_MIN: u64 = 300;
 const SPEED_MAX: u64 = 1_000;
-pub const DEFAULT_DURATION: u64 = 400;
+// the speed percentage the fallback properties start with
+pub const DEFAULT_SPEED: u64 = 50;
+// on a gentle stop each step is this much slower than the last (in quarters), for this many steps
+const DECEL_STEPS: u32 = 12;
+// how long stop_gently gives the ramp before stopping outright
+const DECEL_WAIT: Duration = Duration::from_secs(1);
+// a step thread that hasn't come round its loop in this long is stuck, the slowest step is 2s in test
+const STALL_AFTER: Duration = Duration::from_secs(10);
+
+// a running step thread and the number of its move
+type StepThread = (u64, JoinHandle<()>);
 
 
 impl Motor {
@@ -51,29 +120,72 @@ impl Motor {
         let speed = (((SPEED_MAX - SPEED_MIN) / 100) * val) + SPEED_MIN;
         info!("set speed {}, {}", val, speed);
         self.step_duration.store(speed, Ordering::SeqCst);
+        events::publish(Event::Speed(val as i64));
+    }
+
+    // The speed percentage set_speed would have been given for the step duration now
+    pub fn speed(&self) -> i64 {
+        let duration = self.step_duration.load(Ordering::SeqCst);
+        return (duration.saturating_sub(SPEED_MIN) / ((SPEED_MAX - SPEED_MIN) / 100)).min(100) as i64;
     }
 
-    fn get_input(&self, num: u8, reset: bool) -> InputPin {
-        let mut pin = self.gpio.get(num).unwrap().into_input();
+    fn get_input(&self, num: u8, reset: bool) -> Result<InputPin> {
+        let mut pin = self.gpio.get(num).map_err(|e| WindybleError::gpio(num, "input", e))?.into_input();
         pin.set_reset_on_drop(reset);
-        return pin;
+        return Ok(pin);
     }
-    fn get_output(&self, num: u8, reset: bool) -> OutputPin {
-        let mut pin = self.gpio.get(num).unwrap().into_output();
+    fn get_output(&self, num: u8, reset: bool) -> Result<OutputPin> {
+        let mut pin = self.gpio.get(num).map_err(|e| WindybleError::gpio(num, "output", e))?.into_output();
         pin.set_reset_on_drop(reset);
-        return pin;
+        return Ok(pin);
     }
 
+    fn write_level(&self, num: u8, high: bool) -> Result<()> {
+        let mut pin = self.get_output(num, false)?;
+        if high {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+        return Ok(());
+    }
 
-    pub fn new(gpio_config: GpioConfig, is_test: bool) -> Motor {
-        let gpio = Gpio::new().unwrap();
-        return Motor {
+    // A pin we can't drive is a hardware fault, there's no one to hand the error back to
+    fn pins_ok(&self, result: Result<()>) -> bool {
+        if let Err(e) = result {
+            error::report(&e);
+            fault::raise(Fault::Gpio);
+            return false;
+        }
+        return true;
+    }
+
+
+    pub fn new(gpio_config: GpioConfig, motor_config: MotorConfig, is_test: bool) -> Result<Motor> {
+        let gpio = Gpio::new().map_err(|e| WindybleError::Gpio(format!("can't open the gpio: {}", e)))?;
+        return Ok(Motor {
             gpio_config,
+            motor_config,
             running: Arc::new(AtomicBool::new(false)),
             step_duration: Arc::new(AtomicU64::new(u64::from(SPEED_MAX - SPEED_MIN / 2))),
             is_test,
             gpio,
-        };
+            run_pt: Arc::new(AtomicI64::new(0)),
+            powered: Arc::new(AtomicBool::new(false)),
+            holding: Arc::new(AtomicBool::new(false)),
+            bridged: Arc::new(AtomicBool::new(false)),
+            idle_gen: Arc::new(AtomicU64::new(0)),
+            powered_at: Arc::new(Mutex::new(None)),
+            step_thread: Arc::new(Mutex::new(None)),
+            moves: Arc::new(AtomicU64::new(0)),
+            finishing: Arc::new(Mutex::new(())),
+            beat: Arc::new(AtomicU64::new(0)),
+            position: Arc::new(AtomicI64::new(0)),
+            homed: Arc::new(AtomicBool::new(false)),
+            travel: Arc::new(AtomicI64::new(0)),
+            decelerate: Arc::new(AtomicBool::new(false)),
+            cut: Arc::new(AtomicBool::new(false)),
+        });
     }
 
     // TODO this only sets to lowest value... I think
@@ -86,35 +198,148 @@ impl Motor {
     Low	Low	2 A
      */
     pub fn set_potentiometer(&self, pt_val: &i64) {
+        self.run_pt.store(*pt_val, Ordering::SeqCst);
+        events::publish(Event::Pt(*pt_val));
+        if self.holding.load(Ordering::SeqCst) {
+            // applied when the hold is released before the next step
+            debug!("holding, pt {} applies on next turn", pt_val);
+            return;
+        }
+        self.pins_ok(self.write_potentiometer(pt_val));
+    }
+
+    fn write_potentiometer(&self, pt_val: &i64) -> Result<()> {
         match pt_val {
             1 => {
-                self.get_output(self.gpio_config.pt1, false).set_low();
-                self.get_input(self.gpio_config.pt2, false);
+                self.get_output(self.gpio_config.pt1, false)?.set_low();
+                self.get_input(self.gpio_config.pt2, false)?;
             }
             2 => {
-                self.get_input(self.gpio_config.pt1, false);
-                self.get_output(self.gpio_config.pt2, false).set_low();
+                self.get_input(self.gpio_config.pt1, false)?;
+                self.get_output(self.gpio_config.pt2, false)?.set_low();
             }
             3 => {
-                self.get_output(self.gpio_config.pt1, false).set_low();
-                self.get_output(self.gpio_config.pt2, false).set_low();
+                self.get_output(self.gpio_config.pt1, false)?.set_low();
+                self.get_output(self.gpio_config.pt2, false)?.set_low();
             }
             _ => {
-                self.get_input(self.gpio_config.pt1, false);
-                self.get_input(self.gpio_config.pt2, false);
+                self.get_input(self.gpio_config.pt1, false)?;
+                self.get_input(self.gpio_config.pt2, false)?;
             }
         }
+        return Ok(());
     }
 
 
     pub fn power_motor(&self, on: bool) {
-        debug!("switching motor ({:?}) {}", self.gpio_config.power_relay_pin, if on { "on" } else { "off" });
-        let mut pin = self.get_output(self.gpio_config.power_relay_pin, false);
-        pin.set_reset_on_drop(false);
+        if on && crate::shutting_down() {
+            debug!("shutting down, motor stays off");
+            return;
+        }
+        if !self.pins_ok(self.write_power(on)) {
+            return;
+        }
         if on {
-            pin.set_low();
+            self.arm_hold();
         } else {
-            pin.set_high();
+            self.idle_gen.fetch_add(1, Ordering::SeqCst);
+            self.holding.store(false, Ordering::SeqCst);
+            self.bridged.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /*
+      Power up for a move the server steps through the bridged dir/step pins. We never see the
+      steps, so we'd look idle the whole time, the hold stays off until we're powered down again
+     */
+    pub fn power_bridged(&self) {
+        self.bridged.store(true, Ordering::SeqCst);
+        self.power_motor(true);
+    }
+
+    fn write_power(&self, on: bool) -> Result<()> {
+        let conf = self.gpio_config;
+        debug!("switching motor ({:?} {:?}) {}", conf.power_mode, conf.power_relay_pin, if on { "on" } else { "off" });
+        let use_relay = conf.power_mode != PowerMode::Enable;
+        let use_enable = conf.power_mode != PowerMode::Relay;
+        // supply comes up before the driver is enabled, and the driver is disabled before the supply drops
+        if on && use_relay {
+            self.write_level(conf.power_relay_pin, on == conf.relay_active_high)?;
+        }
+        if use_enable {
+            if let Some(enable_pin) = conf.enable_pin {
+                self.write_level(enable_pin, on == conf.enable_active_high)?;
+            }
+        }
+        if !on && use_relay {
+            self.write_level(conf.power_relay_pin, on == conf.relay_active_high)?;
+        }
+        let was_on = self.powered.swap(on, Ordering::SeqCst);
+        if was_on != on {
+            events::publish(Event::Power(on));
+            metrics::power(on);
+        }
+        let mut powered_at = self.powered_at.lock().unwrap_or_else(|e| e.into_inner());
+        if !on {
+            *powered_at = None;
+        } else if !was_on || powered_at.is_none() {
+            *powered_at = Some(Instant::now());
+        }
+        return Ok(());
+    }
+
+    // Block until the supply has had power_on_settle to come up, or we're told to stop
+    fn wait_for_power(&self) {
+        let settle = self.motor_config.power_on_settle;
+        loop {
+            let powered_at = *self.powered_at.lock().unwrap_or_else(|e| e.into_inner());
+            let waited = match powered_at {
+                Some(at) => at.elapsed(),
+                None => Duration::from_secs(0),
+            };
+            if (powered_at.is_some() && waited >= settle) || !self.is_running() {
+                return;
+            }
+            self.heartbeat();
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    /*
+      Start the idle timer, if the motor is still powered and hasn't stepped by the time
+      it runs out we drop the current (or the power) until the next turn
+     */
+    fn arm_hold(&self) {
+        let hold_after = self.motor_config.hold_after;
+        if hold_after.as_secs() == 0 {
+            return;
+        }
+        let gen = self.idle_gen.fetch_add(1, Ordering::SeqCst) + 1;
+        let clone = self.clone();
+        task::spawn(async move {
+            task::sleep(hold_after).await;
+            if clone.idle_gen.load(Ordering::SeqCst) != gen
+                || clone.is_running()
+                || clone.bridged.load(Ordering::SeqCst)
+                || !clone.powered.load(Ordering::SeqCst) {
+                return;
+            }
+            info!("Motor idle for {:?}, holding ({:?})", hold_after, clone.motor_config.hold_mode);
+            clone.holding.store(true, Ordering::SeqCst);
+            clone.pins_ok(match clone.motor_config.hold_mode {
+                HoldMode::Lowest => clone.write_potentiometer(&0),
+                HoldMode::Off => clone.write_power(false),
+            });
+        });
+    }
+
+    // Put the configured current limit back before stepping again
+    fn release_hold(&self) {
+        self.idle_gen.fetch_add(1, Ordering::SeqCst);
+        if self.holding.swap(false, Ordering::SeqCst) {
+            let pt = self.run_pt.load(Ordering::SeqCst);
+            debug!("release hold, restore pt {}", pt);
+            self.pins_ok(self.write_potentiometer(&pt));
         }
     }
 
@@ -122,56 +347,390 @@ impl Motor {
         return self.running.load(Ordering::SeqCst);
     }
 
-    pub fn turn(&self, dir: u8) -> bool {
+    pub fn is_powered(&self) -> bool {
+        return self.powered.load(Ordering::SeqCst);
+    }
+
+    // The step thread is still coming round its loop, or there's no move for it to be stuck in
+    pub fn is_responsive(&self) -> bool {
+        if !self.is_running() {
+            return true;
+        }
+        let since = events::now_millis().saturating_sub(self.beat.load(Ordering::SeqCst));
+        return since < STALL_AFTER.as_millis() as u64;
+    }
+
+    fn heartbeat(&self) {
+        self.beat.store(events::now_millis(), Ordering::SeqCst);
+    }
+
+    pub fn is_holding(&self) -> bool {
+        return self.holding.load(Ordering::SeqCst);
+    }
+
+    // the pt value the motor drives at, even while holding at a lower one
+    pub fn current_limit(&self) -> i64 {
+        return self.run_pt.load(Ordering::SeqCst);
+    }
+
+    pub fn turn(&self, dir: u8, source: Source) -> bool {
+        return self.turn_steps(dir, None, source).is_some();
+    }
+
+    /*
+      Steps in dir until stopped, a limit switch is hit, or steps have been taken.
+      The position is counted from the top stop, up (COUNTER_CLOCKWISE) counts down.
+      source is who asked, for the journal. The number of the move when it started
+     */
+    fn turn_steps(&self, dir: u8, steps: Option<u64>, source: Source) -> Option<u64> {
+        // waits out a stop that is still powering down before we start again
+        let _finishing = self.finishing.lock().unwrap_or_else(|e| e.into_inner());
+        let mut step_thread = self.step_thread.lock().unwrap_or_else(|e| e.into_inner());
         if self.is_running() {
             info!("Already turning!");
-            return false;
+            return None;
+        }
+        if crate::shutting_down() {
+            info!("Shutting down, not turning");
+            return None;
+        }
+        if crate::at_limit(dir) {
+            info!("Already at the limit for direction {}", dir);
+            return None;
         }
 
-        self.power_motor(true);
+        if !self.set_direction(dir) {
+            return None;
+        }
 
-        self.set_direction(dir);
+        self.release_hold();
+        self.decelerate.store(false, Ordering::SeqCst);
+        self.cut.store(false, Ordering::SeqCst);
+        self.heartbeat();
+        self.running.store(true, Ordering::SeqCst);
+        events::publish(Event::Running(true));
+        self.power_motor(true);
         let clone = self.clone();
         let speed = if self.is_test { 1_000_000 } else { self.step_duration.load(Ordering::SeqCst) };
-        let duration = Duration::from_micros(speed);
-        self.running.store(true, Ordering::SeqCst);
-        let run_clone = self.running.clone();
-        thread::spawn(move || {
-            let mut step_pin = clone.gpio.get(clone.gpio_config.step).expect("Failed to unwrap step pin").into_output();
-            while run_clone.load(Ordering::SeqCst) {
-                step_pin.set_high();
-                sleep(duration);
-                step_pin.set_low();
-                sleep(duration);
+        let journal_move = journal::Move {
+            source,
+            start: chrono::Local::now(),
+            dir,
+            step_us: speed,
+            pt: self.current_limit(),
+        };
+        let handle = thread::spawn(move || {
+            // nothing else is watching the pins while this runs, a panic here is dealt with here
+            let run = panic::catch_unwind(AssertUnwindSafe(|| clone.step(dir, steps, speed, &journal_move)));
+            if let Err(cause) = run {
+                clone.step_panicked(cause.as_ref(), &journal_move);
             }
-            step_pin.set_low();
-            info!("Motor Done turning");
         });
+        let number = self.moves.fetch_add(1, Ordering::SeqCst) + 1;
+        *step_thread = Some((number, handle));
+        return Some(number);
+    }
+
+    // The step thread, until it's stopped, reaches a limit or the steps are taken
+    fn step(&self, dir: u8, steps: Option<u64>, speed: u64, journal_move: &journal::Move) {
+        let mut duration = Duration::from_micros(speed);
+        let delta: i64 = if dir == PinDir::COUNTER_CLOCKWISE { -1 } else { 1 };
+        let step = self.gpio_config.step;
+        let mut step_pin = match self.gpio.get(step) {
+            Ok(pin) => pin.into_output(),
+            Err(e) => {
+                self.pins_ok(Err(WindybleError::gpio(step, "step", e)));
+                self.running.store(false, Ordering::SeqCst);
+                events::publish(Event::Running(false));
+                journal::record(journal_move, 0, Ended::Fault, self.position());
+                return;
+            }
+        };
+        self.wait_for_power();
+        let started = Instant::now();
+        let mut taken: u64 = 0;
+        let mut ended = Ended::Stopped;
+        let mut slowed: u32 = 0;
+        while self.running.load(Ordering::SeqCst) {
+            self.heartbeat();
+            if self.decelerate.load(Ordering::SeqCst) {
+                if self.is_test || slowed >= DECEL_STEPS {
+                    break;
+                }
+                duration = duration * 5 / 4;
+                slowed += 1;
+            }
+            if crate::at_limit(dir) {
+                info!("Limit reached after {} steps", taken);
+                ended = Ended::Limit;
+                break;
+            }
+            if steps.is_some_and(|n| taken >= n) {
+                ended = Ended::Target;
+                break;
+            }
+            if self.overtravel(dir) {
+                fault::raise(Fault::Overtravel);
+                ended = Ended::Fault;
+                break;
+            }
+            step_pin.set_high();
+            sleep(duration);
+            step_pin.set_low();
+            sleep(duration);
+            self.position.fetch_add(delta, Ordering::SeqCst);
+            taken += 1;
+        }
+        step_pin.set_low();
+        if ended == Ended::Stopped && self.cut.load(Ordering::SeqCst) {
+            ended = Ended::Watchdog;
+        } else if ended == Ended::Stopped && self.decelerate.load(Ordering::SeqCst) {
+            ended = Ended::Shutdown;
+        }
+        self.running.store(false, Ordering::SeqCst);
+        events::publish(Event::Running(false));
+        info!("Motor Done turning, {} steps, position {}", taken, self.position());
+        metrics::record_move(dir, taken, started.elapsed());
+        journal::record(journal_move, taken, ended, self.position());
+        self.arm_hold();
+    }
+
+    /*
+      The step thread panicked part way through a move. Power goes off straight away rather
+      than when someone gets round to joining it, and running is cleared so the next move
+      isn't refused as already turning
+     */
+    fn step_panicked(&self, cause: &(dyn Any + Send), journal_move: &journal::Move) {
+        error!("step thread panicked: {}", supervisor::panic_message(cause));
+        self.cut_power();
+        events::publish(Event::Running(false));
+        fault::raise(Fault::StepThread);
+        journal::record(journal_move, 0, Ended::Fault, self.position());
+    }
+
+    /*
+      Move a fixed distance, positive goes down (CLOCKWISE) and negative goes up.
+      Runs in the background and powers off once the steps are taken
+     */
+    pub fn jog(&self, distance: Distance, source: Source) -> bool {
+        let number = match self.start_jog(distance, source) {
+            Some(n) => n,
+            None => return false,
+        };
+        let clone = self.clone();
+        task::spawn_blocking(move || clone.end_jog(number));
         return true;
     }
 
+    // jog for the command line, returns once the steps are taken and the power is off
+    pub fn jog_and_wait(&self, distance: Distance, source: Source) -> bool {
+        let number = match self.start_jog(distance, source) {
+            Some(n) => n,
+            None => return false,
+        };
+        self.end_jog(number);
+        return true;
+    }
+
+    fn start_jog(&self, distance: Distance, source: Source) -> Option<u64> {
+        let steps = match distance {
+            Distance::Steps(n) => n,
+            Distance::Mm(mm) => match self.steps_for_mm(mm) {
+                Some(n) => n,
+                None => {
+                    warn!("Can't jog {}mm before the travel is calibrated", mm);
+                    return None;
+                }
+            }
+        };
+        if steps == 0 {
+            return None;
+        }
+        let dir = if steps < 0 { PinDir::COUNTER_CLOCKWISE } else { PinDir::CLOCKWISE };
+        info!("jog {} steps", steps);
+        return self.turn_steps(dir, Some(steps.unsigned_abs()), source);
+    }
+
+    /*
+      Powers off once the jog's steps are taken. A stop may have finished it already, or another
+      move started since, then it's theirs to power off
+     */
+    fn end_jog(&self, number: u64) {
+        while self.is_running() && self.moves.load(Ordering::SeqCst) == number {
+            sleep(Duration::from_millis(50));
+        }
+        self.finish_move(Some(number));
+    }
+
+    // Move to a percentage of the calibrated travel, 0 is the top
+    pub fn go_to(&self, percent: i64, source: Source) -> bool {
+        let travel = match self.travel() {
+            Some(t) if self.homed.load(Ordering::SeqCst) => t,
+            _ => {
+                warn!("Can't go to {}% before the travel is calibrated", percent);
+                return false;
+            }
+        };
+        let target = travel * percent.clamp(0, 100) / 100;
+        info!("go to {}% ({} of {} steps)", percent, target, travel);
+        return self.jog(Distance::Steps(target - self.position()), source);
+    }
+
+    // A tenth of the travel past either end without the limit switch closing
+    fn overtravel(&self, dir: u8) -> bool {
+        let travel = match self.travel() {
+            Some(t) if self.homed.load(Ordering::SeqCst) => t,
+            _ => return false,
+        };
+        let margin = travel / 10;
+        let position = self.position();
+        return match dir {
+            PinDir::COUNTER_CLOCKWISE => position < -margin,
+            _ => position > travel + margin,
+        };
+    }
+
+    pub fn position_percent(&self) -> Option<i64> {
+        if !self.homed.load(Ordering::SeqCst) {
+            return None;
+        }
+        return self.travel().map(|travel| (self.position() * 100 / travel).clamp(0, 100));
+    }
+
+    // Wait for the current move to end on its own, a limit switch or a stop
+    pub async fn stopped(&self) {
+        while self.is_running() {
+            task::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /*
+      For shutting down, a move in progress slows over a few steps rather than stopping dead
+      with the load on it, then it's stopped and powered off as usual
+     */
+    pub fn stop_gently(&self) {
+        if self.is_running() {
+            info!("slowing to a stop");
+            self.decelerate.store(true, Ordering::SeqCst);
+            let deadline = Instant::now() + DECEL_WAIT;
+            while self.is_running() && Instant::now() < deadline {
+                sleep(Duration::from_millis(5));
+            }
+        }
+        self.stop();
+    }
+
     pub fn stop(&self) {
         info!("....... STOP");
         self.running.store(false, Ordering::SeqCst);
+        self.finish();
+    }
+
+    fn finish(&self) {
+        self.finish_move(None);
+    }
+
+    /*
+      Power is only cut once the step thread has actually exited and the
+      power_off_settle delay has passed, so the last step completes with the driver powered.
+      With only set nothing happens unless that move's step thread is the one still to join
+     */
+    fn finish_move(&self, only: Option<u64>) {
+        // one at a time until power is off, so a second stop can't cut power early
+        let _finishing = self.finishing.lock().unwrap_or_else(|e| e.into_inner());
+        let handle = {
+            let mut step_thread = self.step_thread.lock().unwrap_or_else(|e| e.into_inner());
+            match (only, step_thread.as_ref()) {
+                (Some(number), Some((n, _))) if *n != number => return,
+                (Some(_), None) => return,
+                _ => step_thread.take(),
+            }
+        };
+        if let Some((_, h)) = handle {
+            if h.join().is_err() {
+                warn!("step thread panicked");
+                fault::raise(Fault::StepThread);
+            }
+        }
+        sleep(self.motor_config.power_off_settle);
         self.power_motor(false);
     }
 
+    pub fn position(&self) -> i64 {
+        return self.position.load(Ordering::SeqCst);
+    }
+
+    /*
+      Where a graceful shutdown left the motor, see src/state.rs. The blind may have been moved
+      by hand since, so it isn't homed until the top stop says so again
+     */
+    pub fn restore(&self, position: i64, travel: Option<i64>) {
+        info!("restored position {}, travel {:?}, not homed until the top stop", position, travel);
+        self.position.store(position, Ordering::SeqCst);
+        self.travel.store(travel.unwrap_or(0), Ordering::SeqCst);
+        self.homed.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_homed(&self) -> bool {
+        return self.homed.load(Ordering::SeqCst);
+    }
+
+    // The top limit switch is position 0
+    pub fn set_top(&self) {
+        debug!("top reached at {}, resetting position", self.position());
+        self.position.store(0, Ordering::SeqCst);
+        self.homed.store(true, Ordering::SeqCst);
+    }
+
+    // Reaching the bottom after the top gives us the full travel in steps
+    pub fn set_bottom(&self) {
+        let position = self.position();
+        if self.homed.load(Ordering::SeqCst) && position > 0 {
+            info!("calibrated travel: {} steps", position);
+            self.travel.store(position, Ordering::SeqCst);
+        }
+    }
+
+    pub fn travel(&self) -> Option<i64> {
+        let travel = self.travel.load(Ordering::SeqCst);
+        return if travel > 0 { Some(travel) } else { None };
+    }
+
+    // needs both a calibrated travel and the configured travel_mm
+    pub fn steps_for_mm(&self, mm: i64) -> Option<i64> {
+        let travel_mm = self.motor_config.travel_mm;
+        if travel_mm <= 0 {
+            return None;
+        }
+        return self.travel().map(|travel| mm * travel / travel_mm);
+    }
 
-    pub fn set_direction(&self, dir: u8) {
+    // False when the dir pin couldn't be set, the motor mustn't step then
+    pub fn set_direction(&self, dir: u8) -> bool {
         info!("SET DIRECTION {:?}", dir);
         // self.dir_pin.set_value(dir.as_u8()).expect("Failed to set direction");
-        match dir {
+        let set = match dir {
             PinDir::COUNTER_CLOCKWISE => {
                 debug!("<<< set dir low");
                 // self.gpio.get(self.gpio_config.dir).unwrap().into_output().set_low();
-                self.get_output(self.gpio_config.dir, false).set_low();
+                self.pins_ok(self.write_level(self.gpio_config.dir, false))
             }
             _ => {
                 debug!("<<< set dir high");
                 // self.gpio.get(self.gpio_config.dir).unwrap().into_output().set_high();
-                self.get_output(self.gpio_config.dir, false).set_high();
+                self.pins_ok(self.write_level(self.gpio_config.dir, true))
             }
+        };
+        // every move comes through here, turn, jog, goto and calibration alike
+        if set {
+            crate::store_direction(dir);
         }
+        return set;
+    }
+
+    // The way the dir pin was last set, so the way a running motor is going
+    pub fn direction(&self) -> u8 {
+        return crate::current_direction();
     }
 
     pub fn init(&self, init_pt: &i64) {
@@ -194,14 +753,31 @@ impl Motor {
     }
 
 
+    /*
+      Leaves the pins in a known safe state for exiting: driver disabled and relay open, pt pins
+      back to inputs (lowest current) and step and dir low. None of them reset on drop, don't
+      unexport the power pin or the 12 volt relay will close
+     */
     pub fn done(&self) {
-        // self.dir_pin.unexport().expect("Failed to un un export DIR pin");
-        // self.step_pin.unexport().expect("Failed to un un export STEP pin");
-        // dont une-export the power pin or the 12 volt relay will close,
-        // best to just leave this on for now
-        //self.power_pin.unexport().expect("Failed to un un export PWR pin");
-        // self.pt_pin_1.unexport().expect("Failed to un export pt1");
-        // self.pt_pin_2.unexport().expect("Failed to un export pt2");
-        info!("En-exported pins for motor");
+        self.idle_gen.fetch_add(1, Ordering::SeqCst);
+        self.holding.store(false, Ordering::SeqCst);
+        // each is tried even if one before it failed
+        let safe = [
+            self.write_power(false),
+            self.write_potentiometer(&0),
+            self.write_level(self.gpio_config.step, false),
+            self.write_level(self.gpio_config.dir, false),
+        ];
+        let failed = safe.iter().filter(|r| !self.pins_ok((*r).clone())).count();
+        if failed == 0 {
+            info!("Motor pins left safe");
+        }
+    }
+
+    // Last resort when shutdown is stuck, power off without waiting on the step thread
+    pub fn cut_power(&self) {
+        self.cut.store(true, Ordering::SeqCst);
+        self.running.store(false, Ordering::SeqCst);
+        self.pins_ok(self.write_power(false));
     }
 }
\ No newline at end of file
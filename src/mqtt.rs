@@ -0,0 +1,169 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::config::MqttConfig;
+use crate::controller::Controller;
+use crate::journal::Source;
+use crate::lockout;
+use crate::supervisor;
+use crate::{current_move_state, MoveState, PinDir};
+
+/*
+  Publishes the blind to Home Assistant as an mqtt cover, topics are under windyble/<node_id>:
+
+    availability    online/offline, offline is the last will
+    state           opening, closing, open, closed or stopped
+    position        0 closed (bottom stop) to 100 open (top stop), only once calibrated
+    set             OPEN, CLOSE or STOP
+    set_position    0 - 100
+
+  The discovery config goes to <discovery_prefix>/cover/<node_id>/config. To try it locally run
+  mosquitto, set [mqtt] host = "127.0.0.1" and watch with mosquitto_sub -v -t 'windyble/#'
+ */
+const STATE_POLL: Duration = Duration::from_millis(250);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub fn start(conf: MqttConfig, controller: Controller) {
+    let host = match conf.host.clone() {
+        Some(h) => h,
+        None => return,
+    };
+    let base = format!("windyble/{}", conf.node_id);
+    let availability = format!("{}/availability", base);
+
+    let mut options = MqttOptions::new(conf.node_id.as_str(), host.as_str(), conf.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(availability.as_str(), "offline", QoS::AtLeastOnce, true));
+    if let (Some(user), Some(pass)) = (conf.username.clone(), conf.password.clone()) {
+        options.set_credentials(user, pass);
+    }
+    let (client, connection) = Client::new(options, 10);
+    // kept across restarts of the thread below, a panic there leaves it as it was
+    let connection = Mutex::new(connection);
+
+    // incoming commands, and (re)announcing ourselves every time we connect
+    supervisor::thread("mqtt", {
+        let client = client.clone();
+        let controller = controller.clone();
+        let base = base.clone();
+        move || {
+            info!("mqtt connecting to {}:{}", host, conf.port);
+            let mut connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("mqtt connected");
+                        announce(&client, &conf, &base);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        let payload = String::from_utf8_lossy(&p.payload).to_string();
+                        handle_command(&controller, &base, p.topic.as_str(), payload.trim());
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("mqtt connection error: {:?}", e);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        }
+    });
+
+    // publish state and position whenever they change
+    supervisor::thread("mqtt state", move || {
+        let mut last: Option<(&'static str, Option<i64>)> = None;
+        loop {
+            let current = (cover_state(&controller), ha_position(&controller));
+            if last != Some(current) {
+                let (state, position) = current;
+                publish(&client, format!("{}/state", base), state.to_string());
+                if let Some(p) = position {
+                    publish(&client, format!("{}/position", base), p.to_string());
+                }
+                last = Some(current);
+            }
+            thread::sleep(STATE_POLL);
+        }
+    });
+}
+
+fn announce(client: &Client, conf: &MqttConfig, base: &str) {
+    let discovery = json!({
+        "name": conf.name,
+        "unique_id": conf.node_id,
+        "device_class": "blind",
+        "availability_topic": format!("{}/availability", base),
+        "state_topic": format!("{}/state", base),
+        "command_topic": format!("{}/set", base),
+        "position_topic": format!("{}/position", base),
+        "set_position_topic": format!("{}/set_position", base),
+        "payload_open": "OPEN",
+        "payload_close": "CLOSE",
+        "payload_stop": "STOP",
+        "device": {
+            "identifiers": [conf.node_id],
+            "name": conf.name,
+            "manufacturer": "windyble",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+    publish(client, format!("{}/cover/{}/config", conf.discovery_prefix, conf.node_id), discovery.to_string());
+    publish(client, format!("{}/availability", base), String::from("online"));
+    for topic in &["set", "set_position"] {
+        if let Err(e) = client.subscribe(format!("{}/{}", base, topic), QoS::AtLeastOnce) {
+            error!("mqtt subscribe to {} failed: {:?}", topic, e);
+        }
+    }
+}
+
+fn handle_command(controller: &Controller, base: &str, topic: &str, payload: &str) {
+    debug!("mqtt {} = {}", topic, payload);
+    let command = topic.trim_start_matches(base).trim_start_matches('/');
+    if payload != "STOP" {
+        if let Err(e) = lockout::check() {
+            info!("mqtt {} ignored, {}", payload, e);
+            return;
+        }
+    }
+    match (command, payload) {
+        ("set", "OPEN") => controller.turn(Some(PinDir::COUNTER_CLOCKWISE), Source::Mqtt),
+        ("set", "CLOSE") => controller.turn(Some(PinDir::CLOCKWISE), Source::Mqtt),
+        ("set", "STOP") => controller.stop(),
+        ("set_position", p) => match p.parse::<i64>() {
+            Ok(position) if (0..=100).contains(&position) => {
+                // home assistant's 100 is open, ours is the bottom
+                controller.go_to(100 - position, Source::Mqtt);
+            }
+            _ => warn!("mqtt set_position out of range: {:?}", p),
+        },
+        _ => warn!("unknown mqtt command {} {:?}", topic, payload),
+    }
+}
+
+fn cover_state(controller: &Controller) -> &'static str {
+    let motor = controller.motor();
+    if motor.is_running() {
+        return if motor.direction() == PinDir::COUNTER_CLOCKWISE { "opening" } else { "closing" };
+    }
+    return match current_move_state() {
+        MoveState::UP => "open",
+        MoveState::DOWN => "closed",
+        _ => "stopped",
+    };
+}
+
+fn ha_position(controller: &Controller) -> Option<i64> {
+    return controller.motor().position_percent().map(|p| 100 - p);
+}
+
+fn publish(client: &Client, topic: String, payload: String) {
+    if let Err(e) = client.publish(topic.as_str(), QoS::AtLeastOnce, true, payload) {
+        warn!("mqtt publish to {} failed: {:?}", topic, e);
+    }
+}
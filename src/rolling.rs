@@ -0,0 +1,39 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/*
+  Size rotated text files, shared by the move journal and the log file. Errors are left to the
+  caller, the logger can't log its own failures.
+ */
+
+// Appends a line, creating the file and its directory when needed
+pub fn append(file: &str, line: &str) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(file).parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    let mut f = OpenOptions::new().create(true).append(true).open(file)?;
+    return writeln!(f, "{}", line);
+}
+
+// Rotates once the file has grown past max_size
+pub fn rotate_if_over(file: &str, max_size: u64, keep: u32) -> std::io::Result<()> {
+    if fs::metadata(file).map(|m| m.len() > max_size).unwrap_or(false) {
+        return rotate(file, keep);
+    }
+    return Ok(());
+}
+
+// file.keep is dropped, file.n moves to file.n+1 and file becomes file.1, keep 0 just truncates
+fn rotate(file: &str, keep: u32) -> std::io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(file);
+    }
+    let _ = fs::remove_file(format!("{}.{}", file, keep));
+    for n in (1..keep).rev() {
+        let _ = fs::rename(format!("{}.{}", file, n), format!("{}.{}", file, n + 1));
+    }
+    return fs::rename(file, format!("{}.1", file));
+}
@@ -0,0 +1,449 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::task;
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, Timelike};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::config::Location;
+use crate::controller::Controller;
+use crate::journal::Source;
+use crate::lockout;
+use crate::sun::{self, SunEvent};
+use crate::supervisor;
+use crate::PinDir;
+
+/*
+  Timed opening and closing. An entry is "<days> <time> <open|close> [to <n>%]":
+
+    weekdays 07:00 open to 80%
+    daily 22:00 close
+    sat,sun 09:30 open
+    daily sunrise+15m open
+    weekdays sunset close
+    daily dusk-1h close to 50%
+
+  days is daily, weekdays, weekends or a comma separated list of mon..sun. time is HH:MM, or
+  dawn, sunrise, sunset or dusk (civil twilight) with an optional +/- offset in m or h, which
+  needs a [location]. open/close on their own run to the end stop, "to n%" is how far open or
+  closed to go and needs a calibrated travel.
+
+  When several entries fall on the same minute only one runs: the one with the most specific
+  days (a list of days, then weekdays/weekends, then daily), and the later one in the list
+  when that's a tie.
+
+  Minutes missed to a stall or the clock jumping forward are caught up, but only the latest
+  entry due in them runs, it's where the blind should be now. When the clock goes back, an
+  hour at the end of summer time, nothing runs again until it passes the last minute we
+  checked.
+ */
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+// don't look back more than this many missed minutes after a stall or clock jump
+const MAX_CATCH_UP: i64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    // how far open, None for all the way
+    Open(Option<i64>),
+    // how far closed, None for all the way
+    Close(Option<i64>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum At {
+    Clock(u32, u32),
+    // minutes after the event, negative is before
+    Sun(SunEvent, i64),
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    // bit 0 is monday through bit 6 sunday
+    days: u8,
+    // 2 for a list of days, 1 for weekdays/weekends, 0 for daily
+    specificity: u8,
+    at: At,
+    action: Action,
+    text: String,
+}
+
+impl Entry {
+    pub fn parse(text: &str) -> Result<Entry, String> {
+        let parts: Vec<&str> = text.split_whitespace().collect();
+        if parts.len() < 3 {
+            return Err(format!("expected \"<days> <time> <open|close> [to <n>%]\", got {:?}", text));
+        }
+        let (days, specificity) = parse_days(parts[0])?;
+        let at = parse_time(parts[1])?;
+        let amount = match &parts[3..] {
+            [] => None,
+            ["to", n] => Some(parse_percent(n)?),
+            _ => return Err(format!("unexpected {:?} in {:?}", parts[3..].join(" "), text)),
+        };
+        let action = match parts[2] {
+            "open" => Action::Open(amount),
+            "close" => Action::Close(amount),
+            other => return Err(format!("unknown action {:?}, expected open or close", other)),
+        };
+        return Ok(Entry {
+            days,
+            specificity,
+            at,
+            action,
+            text: parts.join(" "),
+        });
+    }
+
+    fn needs_location(&self) -> bool {
+        return match self.at {
+            At::Sun(_, _) => true,
+            At::Clock(_, _) => false,
+        };
+    }
+
+    fn on_day(&self, date: NaiveDate) -> bool {
+        return self.days & (1 << date.weekday().num_days_from_monday()) != 0;
+    }
+
+    fn is_due(&self, at: &NaiveDateTime, location: Option<Location>) -> bool {
+        return match (self.at, location) {
+            (At::Clock(hour, minute), _) => self.on_day(at.date()) && at.hour() == hour && at.minute() == minute,
+            (At::Sun(event, offset), Some(loc)) => {
+                /*
+                  An offset can take the time past midnight either way, "sunset+3h" can be due
+                  the day after the sunset. The days are the days of the event
+                 */
+                let date = at.date();
+                [date.pred_opt(), Some(date), date.succ_opt()].iter()
+                    .flatten()
+                    .filter(|d| self.on_day(**d))
+                    // None on the days the event doesn't happen, near the poles
+                    .filter_map(|d| sun::time_of(event, *d, loc.latitude, loc.longitude))
+                    .map(|t| t.naive_local() + chrono::Duration::minutes(offset))
+                    .any(|t| t.date() == date && t.hour() == at.hour() && t.minute() == at.minute())
+            }
+            (At::Sun(_, _), None) => false,
+        };
+    }
+}
+
+fn parse_days(s: &str) -> Result<(u8, u8), String> {
+    return match s {
+        "daily" => Ok((0b111_1111, 0)),
+        "weekdays" => Ok((0b001_1111, 1)),
+        "weekends" => Ok((0b110_0000, 1)),
+        list => {
+            let mut days = 0;
+            for d in list.split(',') {
+                let bit = match d {
+                    "mon" => 0,
+                    "tue" => 1,
+                    "wed" => 2,
+                    "thu" => 3,
+                    "fri" => 4,
+                    "sat" => 5,
+                    "sun" => 6,
+                    other => return Err(format!("unknown day {:?}", other)),
+                };
+                days |= 1 << bit;
+            }
+            Ok((days, 2))
+        }
+    };
+}
+
+fn parse_time(s: &str) -> Result<At, String> {
+    if !s.contains(':') {
+        return parse_sun_time(s);
+    }
+    let mut hm = s.splitn(2, ':');
+    let hour = hm.next().and_then(|h| h.parse::<u32>().ok());
+    let minute = hm.next().and_then(|m| m.parse::<u32>().ok());
+    return match (hour, minute) {
+        (Some(h), Some(m)) if h < 24 && m < 60 => Ok(At::Clock(h, m)),
+        _ => Err(format!("bad time {:?}, expected HH:MM", s)),
+    };
+}
+
+// sunrise, sunset+15m, dusk-1h
+fn parse_sun_time(s: &str) -> Result<At, String> {
+    let bad = || format!("bad time {:?}, expected HH:MM or dawn/sunrise/sunset/dusk with an optional +/-<n>m or h", s);
+    let split = s.find(['+', '-']).unwrap_or(s.len());
+    let event = SunEvent::parse(&s[..split]).ok_or_else(bad)?;
+    let offset = &s[split..];
+    if offset.is_empty() {
+        return Ok(At::Sun(event, 0));
+    }
+    let (number, scale) = if offset.ends_with('h') {
+        (offset.trim_end_matches('h'), 60)
+    } else {
+        (offset.trim_end_matches('m'), 1)
+    };
+    // parse keeps the sign, "+15" and "-15" are both fine
+    let minutes = number.parse::<i64>().map_err(|_| bad())? * scale;
+    if minutes.abs() > 12 * 60 {
+        return Err(format!("offset in {:?} is more than 12 hours", s));
+    }
+    return Ok(At::Sun(event, minutes));
+}
+
+fn parse_percent(s: &str) -> Result<i64, String> {
+    return match s.trim_end_matches('%').parse::<i64>() {
+        Ok(n) if (0..=100).contains(&n) => Ok(n),
+        _ => Err(format!("bad percentage {:?}", s)),
+    };
+}
+
+pub fn parse_all(lines: &[String]) -> Result<Vec<Entry>, String> {
+    return lines.iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(Entry::parse)
+        .collect();
+}
+
+// Sun relative entries can't work out a time without knowing where we are
+fn check_location(entries: Vec<Entry>, location: Option<Location>) -> Result<Vec<Entry>, String> {
+    if location.is_none() {
+        if let Some(e) = entries.iter().find(|e| e.needs_location()) {
+            return Err(format!("{:?} needs a [location] latitude and longitude", e.text));
+        }
+    }
+    return Ok(entries);
+}
+
+// The entry that runs when more than one is due, see the rules at the top
+fn pick(due: Vec<&Entry>) -> Option<&Entry> {
+    let mut winner: Option<&Entry> = None;
+    for e in due {
+        if winner.is_none_or(|w| e.specificity >= w.specificity) {
+            winner = Some(e);
+        }
+    }
+    return winner;
+}
+
+/*
+  The entries, shared between the scheduler thread and whatever edits them. Edits are written
+  to the schedule file, which wins over the [schedule] entries in the config on the next start.
+ */
+#[derive(Clone)]
+pub struct Schedule {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    file: Option<String>,
+    location: Option<Location>,
+}
+
+impl Schedule {
+    pub fn new(config_entries: &[String], file: Option<String>, location: Option<Location>) -> Schedule {
+        let from_file = file.as_ref()
+            .and_then(|f| fs::read_to_string(f).ok())
+            .map(|text| text.lines().map(String::from).collect::<Vec<String>>());
+        let lines = from_file.unwrap_or_else(|| config_entries.to_vec());
+        let entries = match parse_all(&lines).and_then(|e| check_location(e, location)) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Invalid schedule, starting with none: {}", e);
+                vec![]
+            }
+        };
+        info!("{} schedule entries", entries.len());
+        return Schedule {
+            entries: Arc::new(Mutex::new(entries)),
+            file,
+            location,
+        };
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        return self.entries.lock().unwrap().iter().map(|e| e.text.clone()).collect();
+    }
+
+    // Replace every entry, nothing changes if any of them is invalid
+    pub fn set_all(&self, lines: &[String]) -> Result<(), String> {
+        let entries = check_location(parse_all(lines)?, self.location)?;
+        *self.entries.lock().unwrap() = entries;
+        self.save();
+        return Ok(());
+    }
+
+    pub fn add(&self, line: &str) -> Result<(), String> {
+        let entry = check_location(vec![Entry::parse(line)?], self.location)?.remove(0);
+        self.entries.lock().unwrap().push(entry);
+        self.save();
+        return Ok(());
+    }
+
+    // index is 1 based, as shown by list
+    pub fn remove(&self, index: usize) -> Result<(), String> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if index == 0 || index > entries.len() {
+                return Err(format!("no schedule entry {}", index));
+            }
+            entries.remove(index - 1);
+        }
+        self.save();
+        return Ok(());
+    }
+
+    fn save(&self) {
+        if let Some(file) = &self.file {
+            let text = self.list().join("\n") + "\n";
+            if let Err(e) = fs::write(file, text) {
+                error!("Failed to save schedule to {}: {:?}", file, e);
+            }
+        }
+    }
+
+    fn due(&self, at: &NaiveDateTime) -> Option<Entry> {
+        let entries = self.entries.lock().unwrap();
+        return pick(entries.iter().filter(|e| e.is_due(at, self.location)).collect()).cloned();
+    }
+
+    // The entry due in the latest minute after last up to now, looking back MAX_CATCH_UP minutes at most
+    fn latest_due(&self, last: &NaiveDateTime, now: &NaiveDateTime) -> Option<Entry> {
+        let mut at = *now;
+        while at > *last && *now - at < chrono::Duration::minutes(MAX_CATCH_UP) {
+            if let Some(entry) = self.due(&at) {
+                return Some(entry);
+            }
+            at -= chrono::Duration::minutes(1);
+        }
+        return None;
+    }
+
+    pub fn start(&self, controller: Controller) {
+        let schedule = self.clone();
+        supervisor::task("schedule", move || {
+            let schedule = schedule.clone();
+            let controller = controller.clone();
+            async move {
+                // the latest minute checked, it never goes back so a repeated hour can't fire twice
+                let mut last = minute_now();
+                loop {
+                    task::sleep(CHECK_EVERY).await;
+                    let now = minute_now();
+                    if now <= last {
+                        continue;
+                    }
+                    if let Some(entry) = schedule.latest_due(&last, &now) {
+                        match lockout::check() {
+                            Ok(()) => {
+                                info!("schedule: {}", entry.text);
+                                run(&controller, entry.action);
+                            }
+                            Err(e) => info!("schedule: skipped {}, {}", entry.text, e),
+                        }
+                    }
+                    last = now;
+                }
+            }
+        });
+    }
+}
+
+pub fn run(controller: &Controller, action: Action) {
+    match action {
+        Action::Open(None) => controller.turn(Some(PinDir::COUNTER_CLOCKWISE), Source::Schedule),
+        Action::Close(None) => controller.turn(Some(PinDir::CLOCKWISE), Source::Schedule),
+        // positions count from the top, so n% open is 100 - n
+        Action::Open(Some(n)) => {
+            controller.go_to(100 - n, Source::Schedule);
+        }
+        Action::Close(Some(n)) => {
+            controller.go_to(n, Source::Schedule);
+        }
+    }
+}
+
+fn minute_now() -> NaiveDateTime {
+    let now = Local::now().naive_local();
+    return now.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 was a monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        return NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap();
+    }
+
+    fn schedule(lines: &[&str]) -> Schedule {
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        return Schedule::new(&lines, None, None);
+    }
+
+    #[test]
+    fn parses_entries() {
+        let e = Entry::parse("weekdays  07:00 open to 80%").unwrap();
+        assert_eq!(e.days, 0b001_1111);
+        assert_eq!(e.specificity, 1);
+        assert_eq!(e.at, At::Clock(7, 0));
+        assert_eq!(e.action, Action::Open(Some(80)));
+        assert_eq!(e.text, "weekdays 07:00 open to 80%");
+
+        let e = Entry::parse("sat,sun 09:30 close").unwrap();
+        assert_eq!(e.days, 0b110_0000);
+        assert_eq!(e.specificity, 2);
+        assert_eq!(e.action, Action::Close(None));
+
+        assert_eq!(Entry::parse("daily sunset+1h close").unwrap().at, At::Sun(SunEvent::Sunset, 60));
+        assert_eq!(Entry::parse("daily dusk-15m close").unwrap().at, At::Sun(SunEvent::Dusk, -15));
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        for bad in ["daily 07:00", "someday 07:00 open", "daily 24:00 open", "daily 07:60 open",
+                    "daily 07:00 shut", "daily 07:00 open to 101%", "daily 07:00 open by 5%",
+                    "daily noon open", "daily sunset+13h close"] {
+            assert!(Entry::parse(bad).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn sun_entries_need_a_location() {
+        let lines = vec![String::from("daily sunrise open")];
+        assert!(check_location(parse_all(&lines).unwrap(), None).is_err());
+    }
+
+    #[test]
+    fn due_on_its_days_and_minute() {
+        let s = schedule(&["weekdays 07:00 open"]);
+        assert!(s.due(&at(1, 7, 0)).is_some());
+        assert!(s.due(&at(1, 7, 1)).is_none());
+        assert!(s.due(&at(1, 6, 59)).is_none());
+        // the 6th was a saturday
+        assert!(s.due(&at(6, 7, 0)).is_none());
+    }
+
+    #[test]
+    fn the_most_specific_entry_wins() {
+        let s = schedule(&["mon 07:00 close", "weekdays 07:00 open to 50%", "daily 07:00 open"]);
+        assert_eq!(s.due(&at(1, 7, 0)).unwrap().action, Action::Close(None));
+        assert_eq!(s.due(&at(2, 7, 0)).unwrap().action, Action::Open(Some(50)));
+        assert_eq!(s.due(&at(6, 7, 0)).unwrap().action, Action::Open(None));
+    }
+
+    #[test]
+    fn the_later_entry_wins_a_tie() {
+        let s = schedule(&["daily 07:00 open", "daily 07:00 close"]);
+        assert_eq!(s.due(&at(1, 7, 0)).unwrap().action, Action::Close(None));
+    }
+
+    #[test]
+    fn catching_up_runs_only_the_latest_entry() {
+        let s = schedule(&["daily 07:00 open", "daily 07:02 close to 30%", "daily 07:10 close"]);
+        let due = s.latest_due(&at(1, 6, 59), &at(1, 7, 3)).unwrap();
+        assert_eq!(due.action, Action::Close(Some(30)));
+        // nothing new after the last minute checked
+        assert!(s.latest_due(&at(1, 7, 2), &at(1, 7, 3)).is_none());
+        // and no further back than MAX_CATCH_UP
+        assert!(s.latest_due(&at(1, 6, 0), &at(1, 7, 9)).is_none());
+        assert!(s.latest_due(&at(1, 6, 0), &at(1, 7, 10)).is_some());
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+
+use crate::motor::Motor;
+
+/*
+  Where the motor was left, saved to the [state] file on a graceful shutdown so the position
+  and calibrated travel survive a restart:
+
+    {"position":1520,"travel":4120}
+
+  A relative file is next to the config file. It's removed once it has been read back. A crash
+  or power cut leaves no file and the motor starts uncalibrated as it always has. Even with the
+  file the blind might have been moved while we were down, so the first goto finds the top stop
+  again before going by the position.
+ */
+pub fn save(file: &Option<String>, motor: &Motor) {
+    let file = match file {
+        Some(f) => f,
+        None => return,
+    };
+    let state = json!({
+        "position": motor.position(),
+        "travel": motor.travel(),
+    });
+    match fs::write(file, state.to_string()) {
+        Ok(()) => info!("saved state to {}", file),
+        Err(e) => error!("Failed to save state to {}: {:?}", file, e),
+    }
+}
+
+pub fn restore(file: &Option<String>, motor: &Motor) {
+    let file = match file {
+        Some(f) => f,
+        None => return,
+    };
+    let text = match fs::read_to_string(file) {
+        Ok(t) => t,
+        Err(_) => {
+            debug!("no saved state in {}", file);
+            return;
+        }
+    };
+    if let Err(e) = fs::remove_file(file) {
+        warn!("Failed to remove state file {}: {:?}", file, e);
+    }
+    let state: Value = match serde_json::from_str(&text) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Ignoring state file {}: {:?}", file, e);
+            return;
+        }
+    };
+    let position = state["position"].as_i64().unwrap_or(0);
+    let travel = state["travel"].as_i64().filter(|t| *t > 0);
+    motor.restore(position, travel);
+}
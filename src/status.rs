@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_std::task;
+use hive::handler::Handler;
+use hive::hive::Hive;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use toml::Value;
+
+use crate::config::HiveConfig;
+use crate::fault;
+use crate::motor::Motor;
+use crate::supervisor;
+use crate::systemd;
+use crate::{current_move_state, MoveState, PinDir};
+
+/*
+  Read-only properties the server keeps up to date on the hive, so a controller can show every
+  blind as it is without asking:
+
+    move_state      "free", "up" or "down", from the limit switches
+    direction       "up" or "down", the way the motor last set its dir pin, for any kind of move
+    running         true while stepping
+    powered         true while the motor has power
+    current_limit   the pt the motor is running with, 0 - 3
+    position        steps down from the top stop
+    version         the windyble version
+    fault           the fault code, 0 when there isn't one, see src/fault.rs
+
+  They take the [hive] prefix like the other properties. Clients leave them to the server, only
+  the server steps so only it knows. Anything else setting one is put back on the next poll.
+ */
+const POLL: Duration = Duration::from_millis(250);
+
+const NAMES: [&str; 8] = ["move_state", "direction", "running", "powered", "current_limit", "position", "version", "fault"];
+
+// what was last sent for each property, by the name without the prefix
+static PUBLISHED: Mutex<BTreeMap<&'static str, Value>> = Mutex::new(BTreeMap::new());
+
+// Each status property with its value now, in the order of NAMES
+pub fn current(motor: &Motor) -> Vec<(&'static str, Value)> {
+    let values = vec![
+        Value::from(match current_move_state() {
+            MoveState::UP => "up",
+            MoveState::DOWN => "down",
+            _ => "free",
+        }),
+        Value::from(if motor.direction() == PinDir::COUNTER_CLOCKWISE { "up" } else { "down" }),
+        Value::from(motor.is_running()),
+        Value::from(motor.is_powered()),
+        Value::from(motor.current_limit()),
+        Value::from(motor.position()),
+        Value::from(env!("CARGO_PKG_VERSION")),
+        Value::from(fault::current().map_or(0, |f| f as i64)),
+    ];
+    return NAMES.iter().cloned().zip(values).collect();
+}
+
+// Changes from the hive that aren't what we sent get forgotten, so the next poll sends ours again
+pub fn guard(hive: &mut Hive, names: &HiveConfig) {
+    for name in NAMES.iter().cloned() {
+        if let Some(property) = hive.get_mut_property(&names.property(name)) {
+            property.on_changed.connect(move |value| {
+                let mut published = PUBLISHED.lock().unwrap_or_else(|e| e.into_inner());
+                if value != published.get(name) {
+                    debug!("status {} was set from the hive, sending ours again", name);
+                    published.remove(name);
+                }
+            });
+        }
+    }
+}
+
+// Sends every property once and then each change, for as long as we run
+pub fn start(names: HiveConfig, handle: Handler, motor: Motor) {
+    supervisor::task("hive status", move || {
+        let names = names.clone();
+        let mut handle = handle.clone();
+        let motor = motor.clone();
+        async move {
+            loop {
+                for (name, value) in current(&motor) {
+                    {
+                        let mut published = PUBLISHED.lock().unwrap_or_else(|e| e.into_inner());
+                        if published.get(name) == Some(&value) {
+                            continue;
+                        }
+                        published.insert(name, value.clone());
+                    }
+                    handle.send_property_value(&names.property(name), Some(&value)).await;
+                    systemd::hive_up();
+                }
+                task::sleep(POLL).await;
+            }
+        }
+    });
+}
+
+// The property a client says hello with, by the name without the prefix, see handshake
+pub fn client_property(names: &HiveConfig) -> String {
+    return format!("client_{}", names.name);
+}
+
+/*
+  A client doesn't send the status, so it shakes hands by sending its version under a property
+  of its own once the hive is running. Once that's gone out the hive is up for it
+ */
+pub fn handshake(names: HiveConfig, handle: Handler) {
+    supervisor::task("hive handshake", move || {
+        let name = names.property(&client_property(&names));
+        let mut handle = handle.clone();
+        async move {
+            handle.send_property_value(&name, Some(&Value::from(env!("CARGO_PKG_VERSION")))).await;
+            systemd::hive_up();
+        }
+    });
+}
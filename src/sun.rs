@@ -0,0 +1,72 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+
+/*
+  Sunrise, sunset and civil twilight for a day and place, worked out offline with the
+  sunrise equation (https://en.wikipedia.org/wiki/Sunrise_equation). Good to about a minute,
+  which is plenty for blinds.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SunEvent {
+    // civil twilight starts, sun 6° below the horizon
+    Dawn,
+    Sunrise,
+    Sunset,
+    // civil twilight ends
+    Dusk,
+}
+
+impl SunEvent {
+    pub fn parse(s: &str) -> Option<SunEvent> {
+        return match s {
+            "dawn" => Some(SunEvent::Dawn),
+            "sunrise" => Some(SunEvent::Sunrise),
+            "sunset" => Some(SunEvent::Sunset),
+            "dusk" => Some(SunEvent::Dusk),
+            _ => None,
+        };
+    }
+
+    // altitude of the sun's centre at the event, sunrise/sunset allow for refraction and the disc
+    fn altitude(&self) -> f64 {
+        return match self {
+            SunEvent::Dawn | SunEvent::Dusk => -6.0,
+            SunEvent::Sunrise | SunEvent::Sunset => -0.833,
+        };
+    }
+
+    fn is_morning(&self) -> bool {
+        return *self == SunEvent::Dawn || *self == SunEvent::Sunrise;
+    }
+}
+
+const J2000: f64 = 2451545.0;
+const UNIX_EPOCH_JD: f64 = 2440587.5;
+
+/*
+  When the event happens on the given local date, None when it doesn't happen at all
+  (midnight sun or polar night). latitude is north positive, longitude east positive.
+ */
+pub fn time_of(event: SunEvent, date: NaiveDate, latitude: f64, longitude: f64) -> Option<DateTime<Local>> {
+    let unix_days = date.signed_duration_since(NaiveDate::from_ymd_opt(1970, 1, 1)?).num_days() as f64;
+    let n = (unix_days + UNIX_EPOCH_JD + 0.5 - J2000 + 0.0008).round();
+    // mean solar time at the longitude
+    let j_star = n - longitude / 360.0;
+    let m = (357.5291 + 0.98560028 * j_star) % 360.0;
+    let m_rad = m.to_radians();
+    let centre = 1.9148 * m_rad.sin() + 0.0200 * (2.0 * m_rad).sin() + 0.0003 * (3.0 * m_rad).sin();
+    let lambda = ((m + centre + 180.0 + 102.9372) % 360.0).to_radians();
+    let transit = J2000 + j_star + 0.0053 * m_rad.sin() - 0.0069 * (2.0 * lambda).sin();
+
+    let sin_decl = lambda.sin() * 23.4397_f64.to_radians().sin();
+    let cos_decl = sin_decl.asin().cos();
+    let phi = latitude.to_radians();
+    let cos_hour_angle = (event.altitude().to_radians().sin() - phi.sin() * sin_decl) / (phi.cos() * cos_decl);
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let julian = if event.is_morning() { transit - hour_angle / 360.0 } else { transit + hour_angle / 360.0 };
+
+    let unix_secs = ((julian - UNIX_EPOCH_JD) * 86400.0).round() as i64;
+    return Utc.timestamp_opt(unix_secs, 0).single().map(|t| t.with_timezone(&Local));
+}
@@ -0,0 +1,112 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use futures::FutureExt;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::fault::{self, Fault};
+use crate::motor::Motor;
+
+/*
+  Keeps the long running workers going: the input pins, the command loop, the schedule and the
+  servers. When one panics the motor's power is cut straight away, since whatever it was doing
+  (watching a limit switch, say) isn't happening any more, a worker fault is raised and the
+  worker is started again after RESTART_DELAY. One that keeps on panicking, or one that can't
+  be restarted like the hive, ends the process with an error and systemd starts us over, see
+  windyble.service.
+ */
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+// more panics than this within RESTART_WINDOW and we give up on the worker
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+static MOTOR: Mutex<Option<Motor>> = Mutex::new(None);
+
+// The motor to make safe when a worker goes down
+pub fn watch(motor: Motor) {
+    *MOTOR.lock().unwrap_or_else(|e| e.into_inner()) = Some(motor);
+}
+
+// An async worker, make is called again for each restart
+pub fn task<F, Fut>(name: &'static str, make: F)
+    where F: Fn() -> Fut + Send + 'static,
+          Fut: Future<Output=()> + Send + 'static {
+    task::spawn(async move {
+        let mut restarts = Restarts::new(name);
+        loop {
+            match AssertUnwindSafe(make()).catch_unwind().await {
+                Ok(()) => return,
+                Err(cause) => restarts.panicked(cause),
+            }
+            task::sleep(RESTART_DELAY).await;
+            info!("restarting {}", name);
+        }
+    });
+}
+
+// A worker that blocks, on a thread of its own
+pub fn thread<F>(name: &'static str, run: F)
+    where F: Fn() + Send + 'static {
+    thread::spawn(move || {
+        let mut restarts = Restarts::new(name);
+        loop {
+            match panic::catch_unwind(AssertUnwindSafe(&run)) {
+                Ok(()) => return,
+                Err(cause) => restarts.panicked(cause),
+            }
+            thread::sleep(RESTART_DELAY);
+            info!("restarting {}", name);
+        }
+    });
+}
+
+// For a worker that can't be started again, make the motor safe and exit for systemd to restart us
+pub fn give_up(name: &str, cause: Box<dyn Any + Send>) -> ! {
+    failed(name, cause.as_ref());
+    error!("{} can't be restarted, exiting", name);
+    process::exit(1);
+}
+
+struct Restarts {
+    name: &'static str,
+    panics: Vec<Instant>,
+}
+
+impl Restarts {
+    fn new(name: &'static str) -> Restarts {
+        return Restarts { name, panics: vec![] };
+    }
+
+    // Returns when the worker should be restarted, exits when it's panicking too often
+    fn panicked(&mut self, cause: Box<dyn Any + Send>) {
+        failed(self.name, cause.as_ref());
+        self.panics.retain(|at| at.elapsed() < RESTART_WINDOW);
+        self.panics.push(Instant::now());
+        if self.panics.len() > MAX_RESTARTS {
+            error!("{} panicked {} times in {:?}, exiting", self.name, self.panics.len(), RESTART_WINDOW);
+            process::exit(1);
+        }
+    }
+}
+
+// What panic! was given, when it was a message
+pub fn panic_message(cause: &(dyn Any + Send)) -> String {
+    return cause.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| cause.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| String::from("unknown cause"));
+}
+
+fn failed(name: &str, cause: &(dyn Any + Send)) {
+    error!("{} panicked: {}", name, panic_message(cause));
+    if let Some(motor) = MOTOR.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        motor.cut_power();
+    }
+    fault::raise(Fault::Worker);
+}
@@ -0,0 +1,152 @@
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use crate::controller::Controller;
+
+/*
+  The sd_notify protocol for running under systemd with Type=notify, see windyble.service.
+  Everything is a no-op when systemd didn't give us a NOTIFY_SOCKET, so running by hand is
+  unaffected. READY waits for the hive, see hive_up. With WatchdogSec set the main loop pings
+  at half the interval, but only while the motor looks healthy, so systemd restarts us if the
+  step thread or the command loop hangs.
+ */
+const STATUS_EVERY: Duration = Duration::from_secs(5);
+
+static READY: Once = Once::new();
+
+/*
+  We're ready once the motor is set up and the hive has taken our first send: the first status
+  on the server, the handshake on a client, see status.rs. Only the first call tells systemd
+ */
+pub fn hive_up() {
+    READY.call_once(|| {
+        info!("hive is up");
+        notify("READY=1");
+    });
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+pub fn status(text: &str) {
+    notify(&format!("STATUS={}", text));
+}
+
+fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) if !p.is_empty() => p,
+        _ => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("sd_notify socket failed: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = send(&socket, &path, state) {
+        warn!("sd_notify {} to {} failed: {:?}", state, path, e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &UnixDatagram, path: &str, state: &str) -> std::io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+    // a leading @ is a socket in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+        return socket.send_to_addr(state.as_bytes(), &addr);
+    }
+    return socket.send_to(state.as_bytes(), path);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(socket: &UnixDatagram, path: &str, state: &str) -> std::io::Result<usize> {
+    return socket.send_to(state.as_bytes(), path);
+}
+
+/*
+  Called from the main loop, tick sends the status line and, when the watchdog is on, the ping.
+  Healthy means the command loop answers and, during a move, the step thread is still
+  stepping, see Motor::is_responsive.
+ */
+pub struct Watchdog {
+    ping_every: Option<Duration>,
+    last_tick: Instant,
+    last_status: String,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Watchdog {
+        let usec = env::var("WATCHDOG_USEC").ok().and_then(|v| v.parse::<u64>().ok());
+        // WATCHDOG_PID is set when the variables were meant for someone else
+        let ours = env::var("WATCHDOG_PID").ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .is_none_or(|pid| pid == process::id());
+        let ping_every = usec.filter(|_| ours).map(|us| Duration::from_micros(us / 2));
+        if let Some(every) = ping_every {
+            info!("systemd watchdog ping every {:?}", every);
+        }
+        return Watchdog {
+            ping_every,
+            last_tick: Instant::now(),
+            last_status: String::new(),
+        };
+    }
+
+    pub async fn tick(&mut self, controller: &Controller) {
+        let every = self.ping_every.map_or(STATUS_EVERY, |p| p.min(STATUS_EVERY));
+        if self.last_tick.elapsed() < every {
+            return;
+        }
+        self.last_tick = Instant::now();
+
+        let text = status_text(controller);
+        if text != self.last_status {
+            status(&text);
+            self.last_status = text;
+        }
+        if self.ping_every.is_some() {
+            match self.check(controller).await {
+                Ok(()) => notify("WATCHDOG=1"),
+                Err(e) => error!("withholding the watchdog ping: {}", e),
+            }
+        }
+    }
+
+    async fn check(&self, controller: &Controller) -> Result<(), String> {
+        if !controller.is_responsive().await {
+            return Err(String::from("the command loop or the step thread is stuck"));
+        }
+        return Ok(());
+    }
+}
+
+// one line for systemctl status
+fn status_text(controller: &Controller) -> String {
+    let status = controller.status();
+    let field = |key: &str| status.get(key).map(|v| v.to_string().trim_matches('"').to_string()).unwrap_or_default();
+    let mut text = if status["running"].as_bool() == Some(true) {
+        format!("moving {}", field("direction"))
+    } else {
+        format!("stopped, {}", field("state"))
+    };
+    if status["percent"].is_i64() {
+        text += &format!(", {}%", field("percent"));
+    }
+    if !status["fault"].is_null() {
+        text += &format!(", fault {}", field("fault"));
+    }
+    if !status["override"].is_null() {
+        text += ", manual override";
+    }
+    return text;
+}
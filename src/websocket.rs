@@ -0,0 +1,73 @@
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use serde_json::json;
+use tungstenite::{accept, Message};
+
+use crate::controller::Controller;
+use crate::events::{self, now_millis};
+use crate::supervisor;
+
+/*
+  Live status for dashboards on ws://<host>:<ws_port>/. A new connection first gets a
+  "status" message with the whole controller status (seq 0), then every event as it happens:
+
+    {"seq": 12, "timestamp": 1600000000000, "type": "running", "value": true}
+ */
+pub fn start(port: u16, controller: Controller) {
+    if port == 0 {
+        return;
+    }
+    supervisor::thread("websocket", move || {
+        let addr = format!("0.0.0.0:{}", port);
+        let listener = match TcpListener::bind(addr.as_str()) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Failed to start websocket on {}: {:?}", addr, e);
+                return;
+            }
+        };
+        info!("websocket listening on {}", addr);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    let controller = controller.clone();
+                    thread::spawn(move || stream_events(s, controller));
+                }
+                Err(e) => warn!("websocket connection failed: {:?}", e),
+            }
+        }
+    });
+}
+
+fn stream_events(stream: TcpStream, controller: Controller) {
+    let peer = stream.peer_addr().ok();
+    // subscribe before the snapshot so nothing falls between the two
+    let receiver = events::subscribe();
+    let mut socket = match accept(stream) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("websocket handshake with {:?} failed: {:?}", peer, e);
+            return;
+        }
+    };
+    debug!("websocket client {:?} connected", peer);
+
+    let snapshot = json!({
+        "seq": 0,
+        "timestamp": now_millis(),
+        "type": "status",
+        "value": controller.status(),
+    });
+    if socket.send(Message::Text(snapshot.to_string())).is_err() {
+        return;
+    }
+    for envelope in receiver.iter() {
+        if let Err(e) = socket.send(Message::Text(envelope.to_json().to_string())) {
+            debug!("websocket client {:?} gone: {:?}", peer, e);
+            break;
+        }
+    }
+}